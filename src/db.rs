@@ -1,13 +1,21 @@
 use std::{
+    collections::HashSet,
     fs,
+    ops::{Deref, DerefMut},
     path::Path,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{bail, Result};
-use bp7::Bundle;
+use crate::error::{D7Error, Result};
+use crate::quota::EvictionCandidate;
+use crate::summary::{SummaryVector, DEFAULT_FALSE_POSITIVE_RATE};
+use bp7::{Bundle, EndpointID};
 use log::{debug, info, warn};
-use rusqlite::{params, Connection, Transaction};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
 
 use bitflags::bitflags;
 
@@ -33,12 +41,23 @@ pub struct BundleEntry {
     pub lifetime: u64,
     pub time_added_to_db: u64,
     pub size: u64,
+    pub src_node_no: Option<u64>,
+    pub src_service_no: Option<u64>,
+    pub dst_node_no: Option<u64>,
+    pub dst_service_no: Option<u64>,
+    /// id of the original bundle if this is a fragment
+    pub parent_bid: Option<String>,
+    pub frag_offset: Option<u64>,
+    /// payload length of the fragment
+    pub frag_length: Option<u64>,
+    pub total_data_length: Option<u64>,
 }
 
 /// Create from a given bundle.
 impl From<&Bundle> for BundleEntry {
     fn from(bundle: &Bundle) -> Self {
         //let size = bundle.to_cbor().len() as u64;
+        let fragment = bundle.primary.has_fragmentation();
         BundleEntry {
             src_name: bundle.primary.source.node(),
             src_service: bundle.primary.source.service_name(),
@@ -47,104 +66,579 @@ impl From<&Bundle> for BundleEntry {
             creation_time: bundle.primary.creation_timestamp.dtntime(),
             seqno: bundle.primary.creation_timestamp.seqno(),
             lifetime: bundle.primary.lifetime.as_secs(),
-            time_added_to_db: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis() as u64,
+            time_added_to_db: unix_time_ms(),
             size: 0,
+            src_node_no: bundle
+                .primary
+                .source
+                .scheme_specific_part_ipn()
+                .map(|addr| addr.node_number()),
+            src_service_no: bundle
+                .primary
+                .source
+                .scheme_specific_part_ipn()
+                .map(|addr| addr.service_number()),
+            dst_node_no: bundle
+                .primary
+                .destination
+                .scheme_specific_part_ipn()
+                .map(|addr| addr.node_number()),
+            dst_service_no: bundle
+                .primary
+                .destination
+                .scheme_specific_part_ipn()
+                .map(|addr| addr.service_number()),
+            parent_bid: fragment.then(|| crate::fragment::parent_bid(bundle)),
+            frag_offset: fragment.then_some(bundle.primary.fragmentation_offset),
+            frag_length: fragment.then(|| bundle.payload().map_or(0, |p| p.len()) as u64),
+            total_data_length: fragment.then_some(bundle.primary.total_data_length),
         }
     }
 }
 
+/// Parses constraint names such as `forward_pending,local_endpoint`, separated by `,` or `|`.
+impl std::str::FromStr for Constraints {
+    type Err = D7Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut constraints = Constraints::empty();
+        for name in s.split([',', '|']).map(str::trim) {
+            constraints |= match name.to_uppercase().as_str() {
+                "" | "NONE" => Constraints::empty(),
+                "DISPATCH_PENDING" => Constraints::DISPATCH_PENDING,
+                "FORWARD_PENDING" => Constraints::FORWARD_PENDING,
+                "REASSEMBLY_PENDING" => Constraints::REASSEMBLY_PENDING,
+                "CONTRAINDICATED" => Constraints::CONTRAINDICATED,
+                "LOCAL_ENDPOINT" => Constraints::LOCAL_ENDPOINT,
+                "DELETED" => Constraints::DELETED,
+                _ => return Err(D7Error::Parse(format!("unknown constraint {}", name))),
+            };
+        }
+        Ok(constraints)
+    }
+}
+
+/// Selection of stored bundles, see the `filter_*` methods of [`D7DB`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BundleFilter {
+    /// every stored bundle
+    All,
+    /// bundles where either src or dst is the given node
+    Node(String),
+    /// bundles where either src or dst matches the given service
+    Service(String),
+    NodeAndService(String, String),
+    /// bundles with any of the given constraints
+    Constraints(Constraints),
+    /// bundles not yet delivered to the given peer
+    NotDeliveredTo(String),
+    /// bundles matching all of the given filters
+    And(Vec<BundleFilter>),
+}
+
+fn parse_constraints(bits: u32) -> Result<Constraints> {
+    Constraints::from_bits(bits)
+        .ok_or_else(|| D7Error::Corrupt(format!("unknown constraint bits {:#b}", bits)))
+}
+
+/// Parses an ipn node or service number given as `ipn:<number>`.
+fn ipn_number(query: &str) -> Option<u64> {
+    query.strip_prefix("ipn:")?.parse().ok()
+}
+
+struct SchemaMigration {
+    version: u32,
+    description: &'static str,
+    run: fn(&Transaction) -> Result<()>,
+}
+
+/// All schema migrations, ordered by version.
+///
+/// Databases created before schema versioning report version 0 and contain
+/// the tables of version 1, therefore every step must tolerate existing objects.
+const SCHEMA_MIGRATIONS: &[SchemaMigration] = &[
+    SchemaMigration {
+        version: 1,
+        description: "initial tables",
+        run: schema_v1_tables,
+    },
+    SchemaMigration {
+        version: 2,
+        description: "ipn node and service numbers",
+        run: schema_v2_ipn_numbers,
+    },
+    SchemaMigration {
+        version: 3,
+        description: "index on bundle IDs",
+        run: schema_v3_bid_index,
+    },
+    SchemaMigration {
+        version: 4,
+        description: "intent log for filesystem changes",
+        run: schema_v4_intents,
+    },
+    SchemaMigration {
+        version: 5,
+        description: "deliveries to peers",
+        run: schema_v5_deliveries,
+    },
+    SchemaMigration {
+        version: 6,
+        description: "fragment offsets",
+        run: schema_v6_fragments,
+    },
+    SchemaMigration {
+        version: 7,
+        description: "registered local endpoints",
+        run: schema_v7_endpoints,
+    },
+    SchemaMigration {
+        version: 8,
+        description: "sequence numbers of local sources",
+        run: schema_v8_sequence_numbers,
+    },
+    SchemaMigration {
+        version: 9,
+        description: "unique bundle IDs",
+        run: schema_v9_unique_bids,
+    },
+];
+
+/// Current version of the database schema.
+pub const SCHEMA_VERSION: u32 = 9;
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = tx.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(1)?;
+        if name == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn schema_v1_tables(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS bundles (
+                  id              INTEGER PRIMARY KEY,
+                  src_name        TEXT,
+                  src_service     TEXT,
+                  dst_name        TEXT,
+                  dst_service     TEXT,
+                  creation_time       INTEGER,
+                  seqno           INTEGER,
+                  lifetime        INTEGER,
+                  time_added_to_db INTEGER,
+                  size            INTEGER
+                  )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS bids (
+                  id                INTEGER PRIMARY KEY,
+                  bid               TEXT NOT NULL,
+                  bundle_idx        INTEGER,
+                  constraints_idx   INTEGER,
+                  path              TEXT
+                  )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS constraints (
+                  id              INTEGER PRIMARY KEY,
+                  constraints     INTEGER
+                  )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn schema_v2_ipn_numbers(tx: &Transaction) -> Result<()> {
+    for column in [
+        "src_node_no",
+        "src_service_no",
+        "dst_node_no",
+        "dst_service_no",
+    ] {
+        if !has_column(tx, "bundles", column)? {
+            tx.execute(
+                &format!("ALTER TABLE bundles ADD COLUMN {} INTEGER", column),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn schema_v3_bid_index(tx: &Transaction) -> Result<()> {
+    tx.execute("CREATE INDEX IF NOT EXISTS bids_bid ON bids (bid)", [])?;
+    Ok(())
+}
+
+fn schema_v4_intents(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS intents (
+            bid TEXT PRIMARY KEY,
+            op TEXT NOT NULL,
+            path TEXT
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn schema_v5_deliveries(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS deliveries (
+            bid TEXT NOT NULL,
+            peer TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            cla TEXT,
+            PRIMARY KEY (bid, peer)
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS deliveries_peer ON deliveries (peer)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn schema_v6_fragments(tx: &Transaction) -> Result<()> {
+    for (column, kind) in [
+        ("parent_bid", "TEXT"),
+        ("frag_offset", "INTEGER"),
+        ("frag_length", "INTEGER"),
+        ("total_data_length", "INTEGER"),
+    ] {
+        if !has_column(tx, "bundles", column)? {
+            tx.execute(
+                &format!("ALTER TABLE bundles ADD COLUMN {} {}", column, kind),
+                [],
+            )?;
+        }
+    }
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS bundles_parent_bid ON bundles (parent_bid)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn schema_v7_endpoints(tx: &Transaction) -> Result<()> {
+    // the destination columns match the ones of the bundles table
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS endpoints (
+            eid TEXT PRIMARY KEY,
+            dst_name TEXT,
+            dst_service TEXT,
+            dst_node_no INTEGER,
+            dst_service_no INTEGER,
+            time_registered INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn schema_v8_sequence_numbers(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS sequence_numbers (
+            src TEXT PRIMARY KEY,
+            seqno INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Drops duplicate bundle IDs inserted by concurrent writers, keeping the first row.
+fn schema_v9_unique_bids(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "DELETE FROM bundles WHERE id IN (SELECT bundle_idx FROM bids WHERE id NOT IN (SELECT MIN(id) FROM bids GROUP BY bid));
+        DELETE FROM constraints WHERE id IN (SELECT constraints_idx FROM bids WHERE id NOT IN (SELECT MIN(id) FROM bids GROUP BY bid));
+        DELETE FROM bids WHERE id NOT IN (SELECT MIN(id) FROM bids GROUP BY bid);
+        DROP INDEX IF EXISTS bids_bid;
+        CREATE UNIQUE INDEX IF NOT EXISTS bids_bid_unique ON bids (bid);",
+    )?;
+    Ok(())
+}
+
+/// Record of a bundle handed to a peer or medium.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub peer: String,
+    /// unix time in milliseconds
+    pub timestamp: u64,
+    /// convergence layer used for the delivery, e.g., `tcp` or `export`
+    pub cla: Option<String>,
+}
+
+/// Current unix time in milliseconds.
+pub(crate) fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Filesystem change that was started but not yet confirmed in the database.
+///
+/// An entry is logged before a bundle file is written or after its row was
+/// deleted, so a crash in between can be repaired on the next open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intent {
+    /// the bundle file is being written, the row is not yet inserted
+    Push,
+    /// the row is deleted, the bundle file is not yet removed
+    Remove,
+}
+
+impl Intent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Intent::Push => "push",
+            Intent::Remove => "remove",
+        }
+    }
+    fn parse(op: &str) -> Result<Self> {
+        match op {
+            "push" => Ok(Intent::Push),
+            "remove" => Ok(Intent::Remove),
+            _ => Err(D7Error::Corrupt(format!("unknown intent {}", op))),
+        }
+    }
+}
+
+/// Maximum number of idle connections kept open per database.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// Counter to give each in-memory database of this process a unique name.
+static NEXT_MEMORY_DB: AtomicUsize = AtomicUsize::new(0);
+
+/// Pool of open connections to the database file.
+///
+/// Connections are handed out exclusively and returned when dropped, if no
+/// idle connection is available a new one is opened.
+#[derive(Debug)]
+struct ConnectionPool {
+    db_file: String,
+    idle: Mutex<Vec<Connection>>,
+    /// keeps a shared in-memory database alive while the pool exists
+    anchor: Option<Mutex<Connection>>,
+}
+
+impl ConnectionPool {
+    fn new(db_file: &str) -> Self {
+        Self {
+            db_file: db_file.to_owned(),
+            idle: Mutex::new(Vec::new()),
+            anchor: None,
+        }
+    }
+    fn in_memory() -> Result<Self> {
+        let db_file = format!(
+            "file:d7s-memory-{}-{}?mode=memory&cache=shared",
+            std::process::id(),
+            NEXT_MEMORY_DB.fetch_add(1, Ordering::Relaxed)
+        );
+        let anchor = Connection::open(&db_file)?;
+        Ok(Self {
+            db_file,
+            idle: Mutex::new(Vec::new()),
+            anchor: Some(Mutex::new(anchor)),
+        })
+    }
+    fn get(&self) -> Result<PooledConnection<'_>> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => {
+                let conn = Connection::open(&self.db_file)?;
+                conn.busy_timeout(Duration::from_secs(5))?;
+                conn.pragma_update(None, "journal_mode", "WAL")?;
+                // durable in WAL mode except for the last transactions on power loss
+                conn.pragma_update(None, "synchronous", "NORMAL")?;
+                conn
+            }
+        };
+        Ok(PooledConnection {
+            pool: self,
+            conn: Some(conn),
+        })
+    }
+}
+
+/// Connection borrowed from a [`ConnectionPool`].
+struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection already returned")
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("connection already returned")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let mut idle = self
+                .pool
+                .idle
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.push(conn);
+            }
+        }
+    }
+}
+
+/// Handle to the bundle database.
+///
+/// Clones share the same connection pool and can be used from multiple threads.
 #[derive(Debug, Clone)]
 pub struct D7DB {
-    db_file: String,
+    pool: Arc<ConnectionPool>,
 }
 
 impl D7DB {
-    /*pub fn new() -> Self {
-        let conn = Connection::open_in_memory().expect("error opening in-memory sqlite database");
-
+    /// Opens a database that only lives in memory, e.g., for tests or relay nodes without persistent state.
+    ///
+    /// All clones share the same database, it is discarded when the last clone is dropped.
+    pub fn open_in_memory() -> Result<Self> {
         let me = Self {
-            db_file: None,
-            in_mem: Some(conn),
+            pool: Arc::new(ConnectionPool::in_memory()?),
         };
-        me.create().expect("error creating tables");
-        me
-    }*/
+        me.create()?;
+        Ok(me)
+    }
     pub fn open(path: &str) -> Result<Self> {
-        let dir_path = Path::new(&path)
-            .parent()
-            .expect("error getting directory path");
-        if !dir_path.exists() {
-            fs::create_dir_all(&dir_path)?;
+        if let Some(dir_path) = Path::new(&path).parent() {
+            if !dir_path.as_os_str().is_empty() && !dir_path.exists() {
+                fs::create_dir_all(dir_path)?;
+            }
         }
 
         let me = Self {
-            db_file: path.to_owned(),
+            pool: Arc::new(ConnectionPool::new(path)),
         };
         me.create()?;
         Ok(me)
     }
-    fn get_connection(&self) -> Result<Connection> {
-        Ok(Connection::open(&self.db_file.clone())?)
+    /// returns true for databases opened with [`D7DB::open_in_memory`]
+    pub fn is_in_memory(&self) -> bool {
+        self.pool.anchor.is_some()
+    }
+    fn get_connection(&self) -> Result<PooledConnection<'_>> {
+        self.pool.get()
     }
+    /// Brings the database schema up to [`SCHEMA_VERSION`].
+    ///
+    /// The schema version is kept in `PRAGMA user_version`, each migration runs
+    /// in its own transaction together with the version update.
     fn create(&self) -> Result<()> {
+        let mut conn = self.get_connection()?;
+        let version = Self::schema_version_of(&conn)?;
+        if version > SCHEMA_VERSION {
+            return Err(D7Error::Outdated(format!(
+                "database schema version {} is newer than supported version {}",
+                version, SCHEMA_VERSION
+            )));
+        }
+        for migration in SCHEMA_MIGRATIONS.iter().filter(|m| m.version > version) {
+            info!(
+                "migrating database schema to version {}: {}",
+                migration.version, migration.description
+            );
+            let tx = conn.transaction()?;
+            (migration.run)(&tx)?;
+            tx.pragma_update(None, "user_version", migration.version)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+    fn schema_version_of(conn: &Connection) -> Result<u32> {
+        Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+    }
+    /// returns the schema version of the database
+    pub fn schema_version(&self) -> Result<u32> {
         let conn = self.get_connection()?;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS bundles (
-                      id              INTEGER PRIMARY KEY,
-                      src_name        TEXT,
-                      src_service     TEXT,
-                      dst_name        TEXT,
-                      dst_service     TEXT,
-                      creation_time       INTEGER,
-                      seqno           INTEGER,
-                      lifetime        INTEGER,
-                      time_added_to_db INTEGER,
-                      size            INTEGER
-                      )",
-            [],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS bids (
-                      id                INTEGER PRIMARY KEY,
-                      bid               TEXT NOT NULL,
-                      bundle_idx        INTEGER,
-                      constraints_idx   INTEGER,
-                      path              TEXT
-                      )",
-            [],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS constraints (
-                      id              INTEGER PRIMARY KEY,
-                      constraints     INTEGER
-                      )",
-            [],
-        )?;
+        Self::schema_version_of(&conn)
+    }
+    fn delete_in(&self, tx: &Transaction, bid: &str) -> Result<()> {
+        let idx = self.find_bundle_number_by_bid(tx, bid)?;
+        tx.execute("DELETE FROM bids WHERE id = ?", [idx.0])?;
+        tx.execute("DELETE FROM bundles WHERE id = ?", [idx.1])?;
+        tx.execute("DELETE FROM constraints WHERE id = ?", [idx.2])?;
+        tx.execute("DELETE FROM deliveries WHERE bid = ?", [bid])?;
         Ok(())
     }
     pub fn delete(&self, bid: &str) -> Result<()> {
-        if !self.exists(bid) {
-            bail!("no such database entry found");
-        }
         let mut conn = self.get_connection()?;
-        conn.pragma_update(None, "synchronous", &"OFF".to_string())?;
         let tx = conn.transaction()?;
-        if let Ok(idx) = self.find_bundle_number_by_bid(&tx, bid) {
-            let mut stmt = tx.prepare("DELETE FROM bids WHERE id = ?")?;
-            stmt.execute([idx.0])?;
-            let mut stmt = tx.prepare("DELETE FROM bundles WHERE id = ?")?;
-            stmt.execute([idx.1])?;
-            let mut stmt = tx.prepare("DELETE FROM constraints WHERE id = ?")?;
-            stmt.execute([idx.2])?;
-        }
+        self.delete_in(&tx, bid)?;
+        tx.execute("DELETE FROM intents WHERE bid = ?", [bid])?;
+        tx.commit()?;
+        Ok(())
+    }
+    /// Deletes the bundle and logs the removal of its file at `path` in one transaction.
+    ///
+    /// The intent must be cleared once the file is gone.
+    pub fn delete_logged(&self, bid: &str, path: Option<&str>) -> Result<()> {
+        let mut conn = self.get_connection()?;
+        let tx = conn.transaction()?;
+        self.delete_in(&tx, bid)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO intents (bid, op, path) VALUES (?1, ?2, ?3)",
+            params![bid, Intent::Remove.as_str(), path],
+        )?;
         tx.commit()?;
         Ok(())
     }
+    /// Logs a pending filesystem change for `bid`, replacing an older one.
+    pub fn log_intent(&self, bid: &str, intent: Intent, path: Option<&str>) -> Result<()> {
+        self.get_connection()?.execute(
+            "INSERT OR REPLACE INTO intents (bid, op, path) VALUES (?1, ?2, ?3)",
+            params![bid, intent.as_str(), path],
+        )?;
+        Ok(())
+    }
+    pub fn clear_intent(&self, bid: &str) -> Result<()> {
+        self.get_connection()?
+            .execute("DELETE FROM intents WHERE bid = ?", [bid])?;
+        Ok(())
+    }
+    /// All filesystem changes that were not confirmed, e.g., due to a crash.
+    pub fn intents(&self) -> Result<Vec<(String, Intent, Option<String>)>> {
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare("SELECT bid, op, path FROM intents")?;
+        let mut rows = stmt.query([])?;
+        let mut intents = Vec::new();
+        while let Some(row) = rows.next()? {
+            let op: String = row.get(1)?;
+            intents.push((row.get(0)?, Intent::parse(&op)?, row.get(2)?));
+        }
+        Ok(intents)
+    }
     pub fn find_bundle_number_by_bid(
         &self,
         tx: &Transaction,
@@ -160,20 +654,19 @@ impl D7DB {
             return Ok((idx, bndl_idx, constraint_idx));
         }
 
-        bail!("bundle ID not found in database");
+        Err(D7Error::NotFound(bid.to_owned()))
     }
     pub fn get_bundle_entry(&self, bid: &str) -> Result<BundleEntry> {
         let mut conn = self.get_connection()?;
-        conn.pragma_update(None, "synchronous", &"OFF".to_string())?;
         let tx = conn.transaction()?;
-        let mut be: BundleEntry = Default::default();
-
         let (_, b_idx, _) = self.find_bundle_number_by_bid(&tx, bid)?;
-        {
+        let be = {
             let mut stmt = tx.prepare("SELECT * FROM bundles WHERE id = ?")?;
             let mut rows = stmt.query([b_idx])?;
-            let row = rows.next()?.expect("bundle id not found in database");
-            be = BundleEntry {
+            let row = rows
+                .next()?
+                .ok_or_else(|| D7Error::Corrupt(format!("bundle entry of {} missing", bid)))?;
+            BundleEntry {
                 src_name: row.get(1)?,
                 src_service: row.get(2)?,
                 dst_name: row.get(3)?,
@@ -183,17 +676,27 @@ impl D7DB {
                 lifetime: row.get(7)?,
                 time_added_to_db: row.get(8)?,
                 size: row.get(9)?,
-            };
-        }
+                src_node_no: row.get(10)?,
+                src_service_no: row.get(11)?,
+                dst_node_no: row.get(12)?,
+                dst_service_no: row.get(13)?,
+                parent_bid: row.get(14)?,
+                frag_offset: row.get(15)?,
+                frag_length: row.get(16)?,
+                total_data_length: row.get(17)?,
+            }
+        };
         tx.commit()?;
         Ok(be)
     }
+    /// Inserts the bundle entries, ids already in the database are skipped.
     pub fn insert_bulk(&self, bes: &[(String, BundleEntry, Option<String>)]) -> Result<()> {
         let mut conn = self.get_connection()?;
-        let tx = conn.transaction()?;
+        // take the write lock before the existence check, other connections may insert the same ids
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
 
         {
-            let mut stmt_bundles = tx.prepare("INSERT INTO bundles (src_name, src_service, dst_name, dst_service, creation_time, seqno, lifetime, time_added_to_db, size) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
+            let mut stmt_bundles = tx.prepare("INSERT INTO bundles (src_name, src_service, dst_name, dst_service, creation_time, seqno, lifetime, time_added_to_db, size, src_node_no, src_service_no, dst_node_no, dst_service_no, parent_bid, frag_offset, frag_length, total_data_length) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
             let mut stmd_contraints = tx.prepare(
                 "INSERT INTO constraints (
                 constraints) VALUES (?1)",
@@ -201,7 +704,12 @@ impl D7DB {
             let mut stmt_idx = tx.prepare(
                 "INSERT INTO bids ( bid, bundle_idx, constraints_idx, path) VALUES ( ?1, ?2, ?3, ?4) ",
             )?;
+            let mut stmt_intent = tx.prepare("DELETE FROM intents WHERE bid = ?")?;
+            let mut stmt_exists = tx.prepare("SELECT 1 FROM bids WHERE bid = ?")?;
             for (bid, be, path) in bes {
+                if stmt_exists.exists([bid])? {
+                    continue;
+                }
                 stmt_bundles.execute(params![
                     be.src_name,
                     be.src_service,
@@ -212,177 +720,389 @@ impl D7DB {
                     be.lifetime,
                     be.time_added_to_db,
                     be.size,
+                    be.src_node_no,
+                    be.src_service_no,
+                    be.dst_node_no,
+                    be.dst_service_no,
+                    be.parent_bid,
+                    be.frag_offset,
+                    be.frag_length,
+                    be.total_data_length,
                 ])?;
 
                 let last_bundle_id = tx.last_insert_rowid();
                 stmd_contraints.execute(params![0])?;
                 let last_constraint_id = tx.last_insert_rowid();
                 stmt_idx.execute(params![bid, last_bundle_id, last_constraint_id, path])?;
+                stmt_intent.execute([bid])?;
             }
         }
         tx.commit()?;
         Ok(())
     }
     pub fn insert(&self, bndl: &Bundle, size: u64, path: Option<String>) -> Result<()> {
-        if self.exists(&bndl.id()) {
-            return Ok(());
-        }
-        let mut conn = self.get_connection()?;
-        conn.pragma_update(None, "synchronous", &"OFF".to_string())?;
-        let tx = conn.transaction()?;
-
         let mut be: BundleEntry = bndl.into();
         be.size = size;
-
-        tx.execute(
-            "INSERT INTO bundles (                
-                src_name,
-                src_service,
-                dst_name,
-                dst_service,
-                creation_time,
-                seqno,
-                lifetime,
-                time_added_to_db,
-                size) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-            params![
-                be.src_name,
-                be.src_service,
-                be.dst_name,
-                be.dst_service,
-                be.creation_time,
-                be.seqno,
-                be.lifetime,
-                be.time_added_to_db,
-                be.size
-            ],
+        self.insert_bulk(&[(bndl.id(), be, path)])
+    }
+    pub fn exists(&self, bid: &str) -> Result<bool> {
+        let conn = self.get_connection()?;
+        let count: usize =
+            conn.query_row("SELECT COUNT(*) FROM bids WHERE bid = ?", [bid], |row| {
+                row.get(0)
+            })?;
+        Ok(count > 0)
+    }
+    pub fn path_for_bundle(&self, bid: &str) -> Result<Option<String>> {
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare("SELECT path FROM bids WHERE bid = ?")?;
+        let mut rows = stmt.query([bid])?;
+        if let Some(row) = rows.next()? {
+            return Ok(row.get(0)?);
+        }
+        Ok(None)
+    }
+    pub fn set_path(&self, bid: &str, path: Option<String>) -> Result<()> {
+        let conn = self.get_connection()?;
+        conn.execute(
+            "UPDATE bids SET path = ?1 WHERE bid = ?2",
+            params![path, bid],
         )?;
-        let last_bundle_id = tx.last_insert_rowid();
-
+        Ok(())
+    }
+    /// Keeps only the id of a deleted bundle so it is not received again.
+    ///
+    /// Sets `DELETED` as the only constraint and clears size and path, the
+    /// removal of the file at `path` is logged in the same transaction.
+    pub fn mark_deleted(&self, bid: &str, path: Option<&str>) -> Result<()> {
+        let mut conn = self.get_connection()?;
+        let tx = conn.transaction()?;
+        let (_, b_idx, c_idx) = self.find_bundle_number_by_bid(&tx, bid)?;
         tx.execute(
-            "INSERT INTO constraints (                
-                constraints) VALUES (?1)",
-            params![0],
+            "UPDATE constraints SET constraints = ?1 WHERE id = ?2",
+            params![Constraints::DELETED.bits(), c_idx],
         )?;
-        let last_constraint_id = tx.last_insert_rowid();
-
+        tx.execute("UPDATE bundles SET size = 0 WHERE id = ?1", [b_idx])?;
+        tx.execute("UPDATE bids SET path = NULL WHERE bid = ?1", [bid])?;
         tx.execute(
-            "INSERT INTO bids ( bid, bundle_idx, constraints_idx, path) VALUES ( ?1, ?2, ?3, ?4) ",
-            params![bndl.id(), last_bundle_id, last_constraint_id, path],
+            "INSERT OR REPLACE INTO intents (bid, op, path) VALUES (?1, ?2, ?3)",
+            params![bid, Intent::Remove.as_str(), path],
         )?;
-
         tx.commit()?;
         Ok(())
     }
-    pub fn exists(&self, bid: &str) -> bool {
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT COUNT(*) FROM bids WHERE bid = ?")
-            .unwrap();
-        //dbg!(name, service, timestamp, seqno);
-        let mut rows = stmt.query([bid]).unwrap();
-        while let Some(row) = rows.next().expect("") {
-            let count: usize = row.get(0).expect("");
-            if count > 0 {
-                return true;
-            };
+    pub fn len(&self) -> Result<usize> {
+        let conn = self.get_connection()?;
+        Ok(conn.query_row("SELECT COUNT(*) FROM bids", [], |row| row.get(0))?)
+    }
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// returns the list of bundle ids in the database
+    pub fn ids(&self) -> Result<Vec<String>> {
+        self.query_bids("SELECT bid FROM bids", [])
+    }
+    /// runs a query selecting a single bid column and collects the results
+    fn query_bids<P: rusqlite::Params>(&self, sql: &str, params: P) -> Result<Vec<String>> {
+        let mut res = Vec::new();
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare(sql)?;
+        let mut rows = stmt.query(params)?;
+        while let Some(row) = rows.next()? {
+            res.push(row.get(0)?);
         }
-        false
+        Ok(res)
     }
-    pub fn path_for_bundle(&self, bid: &str) -> Option<String> {
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn.prepare("SELECT path FROM bids WHERE bid = ?").unwrap();
-        //dbg!(name, service, timestamp, seqno);
-        let mut rows = stmt.query([bid]).unwrap();
-        if let Some(row) = rows
-            .next()
-            .expect("error getting bundle path from database")
-        {
-            return row.get(0).expect("");
+    /// returns a list of known group endpoints
+    pub fn filter_groups(&self, service: &str) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT DISTINCT dst_name FROM bundles WHERE dst_service LIKE ?1",
+            [service],
+        )
+    }
+    /// returns a list of bundle ids where either src or dst is the given node
+    ///
+    /// ipn nodes are given as `ipn:<node number>`
+    ///
+    /// `dtn:none` returns all bundles with an anonymous source or no destination
+    pub fn filter_node(&self, node: &str) -> Result<Vec<String>> {
+        if node == "dtn:none" {
+            return self.filter_dtn_none();
         }
-        None
+        if let Some(node_no) = ipn_number(node) {
+            return self.filter_ipn_node(node_no);
+        }
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE src_name LIKE ?1 OR dst_name LIKE ?1",
+            [node],
+        )
     }
-    pub fn len(&self) -> usize {
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn.prepare("SELECT COUNT(*) FROM bids").unwrap();
-        let mut rows = stmt.query([]).unwrap();
-        rows.next()
-            .expect("unable to count db entries")
-            .unwrap()
-            .get(0)
-            .expect("")
+    /// returns a list of bundle ids where either src or dst matches the given service
+    ///
+    /// ipn services are given as `ipn:<service number>`
+    pub fn filter_service(&self, node: &str) -> Result<Vec<String>> {
+        if let Some(service_no) = ipn_number(node) {
+            return self.filter_ipn_service(service_no);
+        }
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE src_service LIKE ?1 OR dst_service LIKE ?1",
+            [node],
+        )
     }
-    /// returns the list of bundle ids in the database
-    pub fn ids(&self) -> Vec<String> {
-        let mut res: Vec<String> = Vec::new();
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn.prepare("SELECT bid FROM bids").unwrap();
-        //dbg!(name, service, timestamp, seqno);
-        let mut rows = stmt.query([]).unwrap();
-        while let Some(row) = rows.next().expect("") {
-            res.push(row.get(0).expect(""));
+    /// returns a list of bundle ids where either src or dst is `dtn:none`
+    pub fn filter_dtn_none(&self) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE src_name IS NULL OR dst_name IS NULL",
+            [],
+        )
+    }
+    /// returns a list of bundle ids with an anonymous (`dtn:none`) source
+    pub fn filter_anonymous(&self) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE src_name IS NULL",
+            [],
+        )
+    }
+    /// returns a list of bundle ids where either src or dst is the given ipn node number
+    pub fn filter_ipn_node(&self, node_no: u64) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE src_node_no = ?1 OR dst_node_no = ?1",
+            [node_no],
+        )
+    }
+    /// returns a list of bundle ids where either src or dst is the given ipn service number
+    pub fn filter_ipn_service(&self, service_no: u64) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE src_service_no = ?1 OR dst_service_no = ?1",
+            [service_no],
+        )
+    }
+    /// returns a list of bundle ids where either src or dst matches the given name and service
+    ///
+    /// ipn nodes and services are given as `ipn:<number>`
+    pub fn filter_node_and_service(&self, node: &str, service: &str) -> Result<Vec<String>> {
+        if let (Some(node_no), Some(service_no)) = (ipn_number(node), ipn_number(service)) {
+            return self.query_bids(
+                "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE (src_node_no = ?1 OR dst_node_no = ?1) AND (src_service_no = ?2 OR dst_service_no = ?2)",
+                [node_no, service_no],
+            );
         }
-        res
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE (src_name LIKE ?1 OR dst_name LIKE ?1) AND (src_service LIKE ?2 OR dst_service LIKE ?2)",
+            [node, service],
+        )
     }
-    /// returns a list of known group endpoints
-    pub fn filter_groups(&self, service: &str) -> Vec<String> {
-        let mut res = Vec::new();
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT DISTINCT dst_name FROM bundles WHERE dst_service LIKE ?1")
-            .unwrap();
-        let mut rows = stmt.query([service]).unwrap();
-        while let Some(row) = rows.next().expect("") {
-            let bid = row.get(0).expect("");
-            res.push(bid);
+    /// returns a list of bundle ids whose creation time plus lifetime is not after `now`
+    ///
+    /// `now` is given in milliseconds since the DTN epoch, bundles without creation time are skipped
+    pub fn filter_expired(&self, now: u64) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE creation_time != 0 AND creation_time + lifetime * 1000 <= ?1",
+            [now],
+        )
+    }
+    /// returns a list of bundle ids without creation time, their age is tracked in the bundle age block
+    pub fn filter_unknown_creation_time(&self) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE creation_time = 0",
+            [],
+        )
+    }
+    /// Records that `peer` received the bundle, e.g., via the convergence layer `cla`.
+    pub fn mark_delivered(&self, bid: &str, peer: &str, cla: Option<&str>) -> Result<()> {
+        if !self.exists(bid)? {
+            return Err(D7Error::NotFound(bid.to_owned()));
         }
-        res
+        self.get_connection()?.execute(
+            "INSERT OR REPLACE INTO deliveries (bid, peer, timestamp, cla) VALUES (?1, ?2, ?3, ?4)",
+            params![bid, peer, unix_time_ms(), cla],
+        )?;
+        Ok(())
     }
-    /// returns a list of bundle ids where either src or dst is the given node
-    pub fn filter_node(&self, node: &str) -> Vec<String> {
+    /// Forgets the delivery of a bundle to `peer`, e.g. because the peer dropped it.
+    pub fn unmark_delivered(&self, bid: &str, peer: &str) -> Result<()> {
+        self.get_connection()?.execute(
+            "DELETE FROM deliveries WHERE bid = ?1 AND peer = ?2",
+            params![bid, peer],
+        )?;
+        Ok(())
+    }
+    /// returns all peers the bundle was delivered to
+    pub fn delivered_to(&self, bid: &str) -> Result<Vec<Delivery>> {
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT peer, timestamp, cla FROM deliveries WHERE bid = ?1 ORDER BY timestamp",
+        )?;
+        let mut rows = stmt.query([bid])?;
         let mut res = Vec::new();
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE src_name LIKE ?1 OR dst_name LIKE ?1")
-            .unwrap();
-        let mut rows = stmt.query([node]).unwrap();
-        while let Some(row) = rows.next().expect("") {
-            let bid = row.get(0).expect("");
-            res.push(bid);
+        while let Some(row) = rows.next()? {
+            res.push(Delivery {
+                peer: row.get(0)?,
+                timestamp: row.get(1)?,
+                cla: row.get(2)?,
+            });
         }
-        res
+        Ok(res)
     }
-    /// returns a list of bundle ids where either src or dst matches the given service
-    pub fn filter_service(&self, node: &str) -> Vec<String> {
+    /// returns a list of bundle ids not yet delivered to the given peer
+    pub fn pending_for_peer(&self, peer: &str) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT bid FROM bids WHERE NOT EXISTS (SELECT 1 FROM deliveries WHERE deliveries.bid = bids.bid AND peer = ?1)",
+            [peer],
+        )
+    }
+    /// Registers a local endpoint, bundles for it are delivered to local applications.
+    pub fn register_endpoint(&self, eid: &EndpointID) -> Result<()> {
+        let ipn = eid.scheme_specific_part_ipn();
+        self.get_connection()?.execute(
+            "INSERT OR REPLACE INTO endpoints (eid, dst_name, dst_service, dst_node_no, dst_service_no, time_registered) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                eid.to_string(),
+                eid.node(),
+                eid.service_name(),
+                ipn.as_ref().map(|addr| addr.node_number()),
+                ipn.as_ref().map(|addr| addr.service_number()),
+                unix_time_ms()
+            ],
+        )?;
+        Ok(())
+    }
+    pub fn unregister_endpoint(&self, eid: &str) -> Result<()> {
+        let changed = self
+            .get_connection()?
+            .execute("DELETE FROM endpoints WHERE eid = ?1", [eid])?;
+        if changed == 0 {
+            return Err(D7Error::NotFound(eid.to_owned()));
+        }
+        Ok(())
+    }
+    /// Returns the next sequence number for bundles created by `src`, starting at 0.
+    ///
+    /// The counter never resets, so bundle ids stay unique across restarts.
+    pub fn next_seqno(&self, src: &str) -> Result<u64> {
+        let mut conn = self.get_connection()?;
+        let tx = conn.transaction()?;
+        let seqno: Option<u64> = tx
+            .query_row(
+                "SELECT seqno FROM sequence_numbers WHERE src = ?1",
+                [src],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let seqno = seqno.map_or(0, |seqno| seqno + 1);
+        tx.execute(
+            "INSERT OR REPLACE INTO sequence_numbers (src, seqno) VALUES (?1, ?2)",
+            params![src, seqno],
+        )?;
+        tx.commit()?;
+        Ok(seqno)
+    }
+    /// returns all registered local endpoints
+    pub fn endpoints(&self) -> Result<Vec<String>> {
+        self.query_bids("SELECT eid FROM endpoints ORDER BY eid", [])
+    }
+    /// returns the registered endpoint the bundle is destined for
+    pub fn local_endpoint(&self, bid: &str) -> Result<Option<String>> {
+        Ok(self
+            .query_bids(
+                "SELECT eid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx INNER JOIN endpoints ON bundles.dst_name IS endpoints.dst_name AND bundles.dst_service IS endpoints.dst_service AND bundles.dst_node_no IS endpoints.dst_node_no AND bundles.dst_service_no IS endpoints.dst_service_no WHERE bid = ?1",
+                [bid],
+            )?
+            .pop())
+    }
+    /// returns the bundle ids marked `LOCAL_ENDPOINT` for a registered endpoint, oldest first
+    pub fn pending_deliveries(&self, eid: &str) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx INNER JOIN constraints ON constraints.id = bids.constraints_idx INNER JOIN endpoints ON bundles.dst_name IS endpoints.dst_name AND bundles.dst_service IS endpoints.dst_service AND bundles.dst_node_no IS endpoints.dst_node_no AND bundles.dst_service_no IS endpoints.dst_service_no WHERE eid = ?1 AND constraints.constraints & ?2 ORDER BY time_added_to_db, bids.id",
+            params![eid, Constraints::LOCAL_ENDPOINT.bits()],
+        )
+    }
+    /// returns the stored fragments of a bundle as `(bid, offset, length)`, ordered by offset
+    pub fn fragments_of(&self, parent_bid: &str) -> Result<Vec<(String, u64, u64)>> {
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare("SELECT bid, frag_offset, frag_length FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE parent_bid = ?1 ORDER BY frag_offset")?;
+        let mut rows = stmt.query([parent_bid])?;
         let mut res = Vec::new();
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE src_service LIKE ?1 OR dst_service LIKE ?1")
-            .unwrap();
-        let mut rows = stmt.query([node]).unwrap();
-        while let Some(row) = rows.next().expect("") {
-            let bid = row.get(0).expect("");
-            res.push(bid);
+        while let Some(row) = rows.next()? {
+            res.push((row.get(0)?, row.get(1)?, row.get(2)?));
         }
-        res
+        Ok(res)
     }
-    /// returns a list of bundle ids where either src or dst matches the given name and service
-    pub fn filter_node_and_service(&self, node: &str, service: &str) -> Vec<String> {
+    /// returns the ids of original bundles with fragments waiting for reassembly
+    pub fn pending_reassemblies(&self) -> Result<Vec<String>> {
+        self.query_bids(
+            "SELECT DISTINCT parent_bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx INNER JOIN constraints ON constraints.id = bids.constraints_idx WHERE parent_bid IS NOT NULL AND constraints.constraints & ?1",
+            [Constraints::REASSEMBLY_PENDING.bits()],
+        )
+    }
+    /// returns a compact summary of all stored bundle ids to send to a peer
+    pub fn summary_vector(&self) -> Result<SummaryVector> {
+        let ids = self.ids()?;
+        let mut summary = SummaryVector::with_capacity(ids.len(), DEFAULT_FALSE_POSITIVE_RATE);
+        for bid in &ids {
+            summary.insert(bid);
+        }
+        Ok(summary)
+    }
+    /// returns a list of bundle ids the peer that sent `remote` is missing
+    ///
+    /// due to false positives of the summary a few missing bundles may not be listed,
+    /// deleted bundles are never listed as they cannot be provided
+    pub fn missing_in_summary(&self, remote: &SummaryVector) -> Result<Vec<String>> {
+        let mut ids = self.query_bids(
+            "SELECT bid FROM bids INNER JOIN constraints ON constraints.id = bids.constraints_idx WHERE NOT constraints.constraints & ?1",
+            [Constraints::DELETED.bits()],
+        )?;
+        ids.retain(|bid| !remote.contains(bid));
+        Ok(ids)
+    }
+    /// returns the total size of all stored bundles in bytes
+    pub fn total_size(&self) -> Result<u64> {
+        let conn = self.get_connection()?;
+        let size: Option<u64> =
+            conn.query_row("SELECT SUM(size) FROM bundles", [], |row| row.get(0))?;
+        Ok(size.unwrap_or_default())
+    }
+    /// returns the number of bundles that still have their file, i.e. without `DELETED` ones
+    pub fn stored_len(&self) -> Result<usize> {
+        let conn = self.get_connection()?;
+        Ok(conn.query_row(
+            "SELECT COUNT(*) FROM bids INNER JOIN constraints ON constraints.id = bids.constraints_idx WHERE NOT constraints.constraints & ?1",
+            [Constraints::DELETED.bits()],
+            |row| row.get(0),
+        )?)
+    }
+    /// returns all stored bundles with the information needed to decide on eviction
+    ///
+    /// Deleted bundles have no file, evicting them frees nothing.
+    pub fn eviction_candidates(&self) -> Result<Vec<EvictionCandidate>> {
         let mut res = Vec::new();
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT bid FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx WHERE (src_name LIKE ?1 OR dst_name LIKE ?1) AND (src_service LIKE ?2 OR dst_service LIKE ?2)")
-            .unwrap();
-        let mut rows = stmt.query([node, service]).unwrap();
-        while let Some(row) = rows.next().expect("") {
-            let bid = row.get(0).expect("");
-            res.push(bid);
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare("SELECT bid, size, time_added_to_db, creation_time, lifetime, constraints FROM bids INNER JOIN bundles ON bundles.id = bids.bundle_idx INNER JOIN constraints ON constraints.id = bids.constraints_idx WHERE NOT constraints.constraints & ?1")?;
+        let mut rows = stmt.query([Constraints::DELETED.bits()])?;
+        while let Some(row) = rows.next()? {
+            let time_added_to_db: u64 = row.get(2)?;
+            let creation_time: u64 = row.get(3)?;
+            let lifetime: u64 = row.get(4)?;
+            // without creation time the lifetime is counted from reception
+            let start = if creation_time == 0 {
+                time_added_to_db.saturating_sub(bp7::dtntime::SECONDS1970_TO2K * 1000)
+            } else {
+                creation_time
+            };
+            res.push(EvictionCandidate {
+                bid: row.get(0)?,
+                size: row.get(1)?,
+                time_added_to_db,
+                expires: start + lifetime * 1000,
+                constraints: parse_constraints(row.get(5)?)?,
+            });
         }
-        res
+        Ok(res)
     }
+    /// Overwrites the constraints without lifecycle checks, see [`crate::Transition`].
     pub fn set_constraints(&self, bid: &str, constraints: Constraints) -> Result<()> {
         let mut conn = self.get_connection()?;
-        conn.pragma_update(None, "synchronous", &"OFF".to_string())?;
         let tx = conn.transaction()?;
         let (_, _, c_idx) = self.find_bundle_number_by_bid(&tx, bid)?;
         tx.execute(
@@ -398,25 +1118,16 @@ impl D7DB {
         let mut conn = self.get_connection()?;
         let tx = conn.transaction()?;
         let (_, _, c_idx) = self.find_bundle_number_by_bid(&tx, bid)?;
-        //let conn = self.get_connection()?;
-        let mut res: u32 = 0;
-        {
-            let mut stmt =
-                tx.prepare("SELECT constraints FROM constraints WHERE id = ? LIMIT 1")?;
-            let mut rows = stmt.query([c_idx])?;
-            res = rows
-                .next()
-                .expect("error fetching constraints row")
-                .unwrap()
-                .get(0)
-                .expect("error fetching constraints");
-        }
+        let res: u32 = tx.query_row(
+            "SELECT constraints FROM constraints WHERE id = ? LIMIT 1",
+            [c_idx],
+            |row| row.get(0),
+        )?;
         tx.commit()?;
-        Ok(Constraints::from_bits(res).expect("could not parse constraint bits"))
+        parse_constraints(res)
     }
     pub fn add_constraints(&self, bid: &str, constraints: Constraints) -> Result<()> {
         let mut conn = self.get_connection()?;
-        conn.pragma_update(None, "synchronous", &"OFF".to_string())?;
         let tx = conn.transaction()?;
         let (_, _, c_idx) = self.find_bundle_number_by_bid(&tx, bid)?;
         tx.execute(
@@ -430,7 +1141,6 @@ impl D7DB {
     }
     pub fn remove_constraints(&self, bid: &str, constraints: Constraints) -> Result<()> {
         let mut conn = self.get_connection()?;
-        conn.pragma_update(None, "synchronous", &"OFF".to_string())?;
         let tx = conn.transaction()?;
         let (_, _, c_idx) = self.find_bundle_number_by_bid(&tx, bid)?;
         tx.execute(
@@ -439,50 +1149,60 @@ impl D7DB {
             WHERE id = ?2",
             params![constraints.bits(), c_idx],
         )?;
+        tx.commit()?;
         Ok(())
     }
     /// returns the current constraints for all bundle ids in the database
-    pub fn all_constraints(&self) -> Vec<(String, Constraints)> {
+    pub fn all_constraints(&self) -> Result<Vec<(String, Constraints)>> {
         let mut res = Vec::new();
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn.prepare("SELECT bid,constraints FROM bids INNER JOIN constraints ON constraints.id = bids.constraints_idx").unwrap();
-        let mut rows = stmt.query([]).unwrap();
-        while let Some(row) = rows.next().expect("") {
-            let bid = row.get(0).expect("");
-            let constraints = Constraints::from_bits(row.get(1).expect(""))
-                .expect("could not parse constraint bits");
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare("SELECT bid,constraints FROM bids INNER JOIN constraints ON constraints.id = bids.constraints_idx")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let bid = row.get(0)?;
+            let constraints = parse_constraints(row.get(1)?)?;
             res.push((bid, constraints));
         }
-        res
+        Ok(res)
     }
-    /// returns the current constraints for all bundle ids in the database
-    pub fn filter_constraints(&self, constraints: Constraints) -> Vec<String> {
-        let mut res = Vec::new();
-        let conn = self.get_connection().unwrap();
-        let mut stmt = conn.prepare("SELECT bid FROM bids INNER JOIN constraints ON constraints.id = bids.constraints_idx WHERE constraints.constraints & ?1").unwrap();
-        let mut rows = stmt.query([constraints.bits()]).unwrap();
-        while let Some(row) = rows.next().expect("") {
-            let bid = row.get(0).expect("");
-            res.push(bid);
+    /// returns a list of bundle ids matching the given filter
+    pub fn filter(&self, filter: &BundleFilter) -> Result<Vec<String>> {
+        match filter {
+            BundleFilter::All => self.ids(),
+            BundleFilter::Node(node) => self.filter_node(node),
+            BundleFilter::Service(service) => self.filter_service(service),
+            BundleFilter::NodeAndService(node, service) => {
+                self.filter_node_and_service(node, service)
+            }
+            BundleFilter::Constraints(constraints) => self.filter_constraints(*constraints),
+            BundleFilter::NotDeliveredTo(peer) => self.pending_for_peer(peer),
+            BundleFilter::And(filters) => {
+                let mut res = self.ids()?;
+                for filter in filters {
+                    let matching: HashSet<String> = self.filter(filter)?.into_iter().collect();
+                    res.retain(|bid| matching.contains(bid));
+                }
+                Ok(res)
+            }
         }
-        res
+    }
+    /// returns the current constraints for all bundle ids in the database
+    pub fn filter_constraints(&self, constraints: Constraints) -> Result<Vec<String>> {
+        self.query_bids("SELECT bid FROM bids INNER JOIN constraints ON constraints.id = bids.constraints_idx WHERE constraints.constraints & ?1", [constraints.bits()])
     }
     pub fn sync_with_fs(&self, fs: &crate::D7sFs) -> Result<()> {
         info!("syncing db to fs");
 
-        let conn = self.get_connection()?;
-        let mut stmt = conn.prepare("SELECT * FROM bids")?;
-        //dbg!(name, service, timestamp, seqno);
-        let mut rows = stmt.query([])?;
-
-        let all_bids = fs.all_bids();
+        let all_bids: HashSet<String> = fs.all_bids()?.into_iter().collect();
+        // deleted bundles are kept without file
+        let deleted: HashSet<String> = self
+            .filter_constraints(Constraints::DELETED)?
+            .into_iter()
+            .collect();
 
-        while let Some(row) = rows.next()? {
-            let bid: String = row.get(1)?;
-            //let bundle_idx: usize = row.get(2)?;
-            //if let Some(bundle_path) = fs.find_file_by_bid(&bid) {
-            //debug!("path still exists: {}", bundle_path.to_string_lossy())
-            if all_bids.contains(&bid) {
+        // collect first, deleting while iterating would need a second connection
+        for bid in self.ids()? {
+            if all_bids.contains(&bid) || deleted.contains(&bid) {
                 debug!("bid {} present in filesystem", bid);
             } else {
                 warn!(
@@ -498,17 +1218,249 @@ impl D7DB {
 
 #[cfg(test)]
 mod tests {
-    use crate::D7DB;
+    use crate::{SummaryVector, D7DB};
 
     #[test]
     fn simple_db_test() {
         let test_bundle = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
-        //let db = D7DB::new();
-        let db = D7DB::open("/tmp/d7s.db").unwrap();
+        let db = D7DB::open_in_memory().unwrap();
 
-        assert!(!db.exists(&test_bundle.id()));
+        assert!(!db.exists(&test_bundle.id()).unwrap());
         db.insert(&test_bundle, 20, None).unwrap();
-        assert!(db.exists(&test_bundle.id()));
+        assert!(db.exists(&test_bundle.id()).unwrap());
         db.insert(&test_bundle, 20, None).unwrap();
     }
+
+    #[test]
+    fn ipn_db_test() {
+        let src = bp7::EndpointID::with_ipn(23, 42).unwrap();
+        let dst = bp7::EndpointID::with_ipn(7, 1).unwrap();
+        let mut test_bundle = bp7::bundle::new_std_payload_bundle(src, dst, b"ABC".to_vec());
+        test_bundle.primary.creation_timestamp = bp7::CreationTimestamp::now();
+        let db = D7DB::open_in_memory().unwrap();
+
+        db.insert(&test_bundle, 20, None).unwrap();
+        let be = db.get_bundle_entry(&test_bundle.id()).unwrap();
+        assert_eq!(be.src_node_no, Some(23));
+        assert_eq!(be.dst_service_no, Some(1));
+        assert!(db.filter_node("ipn:7").unwrap().contains(&test_bundle.id()));
+        assert!(db
+            .filter_service("ipn:42")
+            .unwrap()
+            .contains(&test_bundle.id()));
+        assert!(db
+            .filter_node_and_service("ipn:23", "ipn:1")
+            .unwrap()
+            .contains(&test_bundle.id()));
+    }
+
+    #[test]
+    fn schema_migration_test() {
+        // a fresh directory also holds the -wal and -shm files of the database
+        let tmp = crate::fs::TempDir::new().unwrap();
+        let path = tmp.path().join("d7s.db");
+        let path = path.to_str().unwrap();
+        {
+            // schema without versioning as created by d7sneakers 0.3
+            let conn = rusqlite::Connection::open(path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE bundles (id INTEGER PRIMARY KEY, src_name TEXT, src_service TEXT, dst_name TEXT, dst_service TEXT, creation_time INTEGER, seqno INTEGER, lifetime INTEGER, time_added_to_db INTEGER, size INTEGER);
+                CREATE TABLE bids (id INTEGER PRIMARY KEY, bid TEXT NOT NULL, bundle_idx INTEGER, constraints_idx INTEGER, path TEXT);
+                CREATE TABLE constraints (id INTEGER PRIMARY KEY, constraints INTEGER);
+                INSERT INTO bundles VALUES (1, 'node1', 'sms', 'node2', 'inbox', 0, 0, 3600, 0, 42);
+                INSERT INTO constraints VALUES (1, 2);
+                INSERT INTO bids VALUES (1, 'dtn://node1/sms-0-0', 1, 1, '/tmp/x.bundle');
+                INSERT INTO bundles VALUES (2, 'node1', 'sms', 'node2', 'inbox', 0, 0, 3600, 0, 42);
+                INSERT INTO constraints VALUES (2, 0);
+                INSERT INTO bids VALUES (2, 'dtn://node1/sms-0-0', 2, 2, NULL);",
+            )
+            .unwrap();
+        }
+        let db = D7DB::open(path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), super::SCHEMA_VERSION);
+        // duplicates are dropped, the first row is kept
+        assert_eq!(db.len().unwrap(), 1);
+        let bid = "dtn://node1/sms-0-0";
+        assert_eq!(db.get_bundle_entry(bid).unwrap().size, 42);
+        assert_eq!(db.get_bundle_entry(bid).unwrap().dst_node_no, None);
+        assert_eq!(
+            db.get_constraints(bid).unwrap(),
+            crate::Constraints::FORWARD_PENDING
+        );
+        assert_eq!(
+            db.path_for_bundle(bid).unwrap().as_deref(),
+            Some("/tmp/x.bundle")
+        );
+
+        // reopening is a no-op
+        let db = D7DB::open(path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), super::SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn concurrent_db_test() {
+        let tmp = crate::fs::TempDir::new().unwrap();
+        let path = tmp.path().join("d7s.db");
+        let path = path.to_str().unwrap();
+        // every thread also inserts the same shared bundles
+        let shared: Vec<_> = (0..20)
+            .map(|_| bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now()))
+            .collect();
+        for db in [D7DB::open(path).unwrap(), D7DB::open_in_memory().unwrap()] {
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let db = db.clone();
+                    let shared = shared.clone();
+                    std::thread::spawn(move || {
+                        for seqno in 0..10 {
+                            let ts = bp7::CreationTimestamp::with_time_and_seq(i, seqno);
+                            let bndl = bp7::helpers::rnd_bundle(ts);
+                            db.insert(&bndl, 20, None).unwrap();
+                            assert!(db.exists(&bndl.id()).unwrap());
+                        }
+                        for bndl in &shared {
+                            db.insert(bndl, 20, None).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            assert_eq!(db.len().unwrap(), 40 + shared.len());
+        }
+    }
+
+    #[test]
+    fn db_errors_test() {
+        let db = D7DB::open_in_memory().unwrap();
+        assert!(matches!(
+            db.get_constraints("dtn://unknown/-0-0"),
+            Err(crate::D7Error::NotFound(_))
+        ));
+        assert!(matches!(
+            db.delete("dtn://unknown/-0-0"),
+            Err(crate::D7Error::NotFound(_))
+        ));
+
+        let test_bundle = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        db.insert(&test_bundle, 20, None).unwrap();
+        db.get_connection()
+            .unwrap()
+            .execute("UPDATE constraints SET constraints = 4096", [])
+            .unwrap();
+        assert!(matches!(
+            db.get_constraints(&test_bundle.id()),
+            Err(crate::D7Error::Corrupt(_))
+        ));
+        assert!(db.all_constraints().is_err());
+    }
+
+    #[test]
+    fn seqno_db_test() {
+        let db = D7DB::open_in_memory().unwrap();
+        assert_eq!(db.next_seqno("dtn://me/chat").unwrap(), 0);
+        assert_eq!(db.next_seqno("dtn://me/chat").unwrap(), 1);
+        assert_eq!(db.next_seqno("dtn://me/mail").unwrap(), 0);
+        assert_eq!(db.next_seqno("dtn://me/chat").unwrap(), 2);
+    }
+
+    #[test]
+    fn deliveries_db_test() {
+        let db = D7DB::open_in_memory().unwrap();
+        let bndl1 = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        let bndl2 = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        db.insert(&bndl1, 0, None).unwrap();
+        db.insert(&bndl2, 0, None).unwrap();
+
+        db.mark_delivered(&bndl1.id(), "dtn://peer/", Some("tcp"))
+            .unwrap();
+        db.mark_delivered(&bndl1.id(), "dtn://usb/", None).unwrap();
+        let peers: Vec<_> = db
+            .delivered_to(&bndl1.id())
+            .unwrap()
+            .into_iter()
+            .map(|d| d.peer)
+            .collect();
+        assert_eq!(peers.len(), 2);
+        assert!(peers.contains(&"dtn://peer/".to_string()));
+        assert_eq!(
+            db.pending_for_peer("dtn://peer/").unwrap(),
+            vec![bndl2.id()]
+        );
+        assert_eq!(db.pending_for_peer("dtn://other/").unwrap().len(), 2);
+        db.unmark_delivered(&bndl1.id(), "dtn://usb/").unwrap();
+        assert_eq!(db.delivered_to(&bndl1.id()).unwrap().len(), 1);
+        assert!(matches!(
+            db.mark_delivered("dtn://unknown/-0-0", "dtn://peer/", None),
+            Err(crate::D7Error::NotFound(_))
+        ));
+
+        db.delete(&bndl1.id()).unwrap();
+        db.insert(&bndl1, 0, None).unwrap();
+        assert!(db.delivered_to(&bndl1.id()).unwrap().is_empty());
+
+        let eid = bndl2.primary.destination.clone();
+        assert_eq!(db.local_endpoint(&bndl2.id()).unwrap(), None);
+        db.register_endpoint(&eid).unwrap();
+        assert_eq!(db.endpoints().unwrap(), vec![eid.to_string()]);
+        assert_eq!(
+            db.local_endpoint(&bndl2.id()).unwrap(),
+            Some(eid.to_string())
+        );
+        assert!(db.pending_deliveries(&eid.to_string()).unwrap().is_empty());
+        db.add_constraints(&bndl2.id(), crate::Constraints::LOCAL_ENDPOINT)
+            .unwrap();
+        assert_eq!(
+            db.pending_deliveries(&eid.to_string()).unwrap(),
+            vec![bndl2.id()]
+        );
+        db.unregister_endpoint(&eid.to_string()).unwrap();
+        assert!(db.unregister_endpoint(&eid.to_string()).is_err());
+        assert_eq!(db.local_endpoint(&bndl2.id()).unwrap(), None);
+    }
+
+    #[test]
+    fn summary_db_test() {
+        let local = D7DB::open_in_memory().unwrap();
+        let remote = D7DB::open_in_memory().unwrap();
+        let bundles: Vec<_> = (0..3)
+            .map(|_| bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now()))
+            .collect();
+        for bndl in &bundles {
+            local.insert(bndl, 0, None).unwrap();
+        }
+        remote.insert(&bundles[0], 0, None).unwrap();
+        let deleted = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        local.insert(&deleted, 0, None).unwrap();
+        local.mark_deleted(&deleted.id(), None).unwrap();
+
+        let summary =
+            SummaryVector::from_bytes(&remote.summary_vector().unwrap().to_bytes()).unwrap();
+        let mut missing = local.missing_in_summary(&summary).unwrap();
+        missing.sort();
+        let mut expected = vec![bundles[1].id(), bundles[2].id()];
+        expected.sort();
+        assert_eq!(missing, expected);
+        assert!(remote
+            .missing_in_summary(&local.summary_vector().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn dtn_none_db_test() {
+        let dst = bp7::EndpointID::with_dtn("//node1/incoming").unwrap();
+        let mut test_bundle =
+            bp7::bundle::new_std_payload_bundle(bp7::EndpointID::none(), dst, b"ABC".to_vec());
+        test_bundle.primary.creation_timestamp = bp7::CreationTimestamp::now();
+        let db = D7DB::open_in_memory().unwrap();
+
+        db.insert(&test_bundle, 20, None).unwrap();
+        assert!(db.filter_anonymous().unwrap().contains(&test_bundle.id()));
+        assert!(db
+            .filter_node("dtn:none")
+            .unwrap()
+            .contains(&test_bundle.id()));
+    }
 }
@@ -0,0 +1,77 @@
+use std::fmt;
+
+/// Errors returned by the store.
+#[derive(Debug)]
+pub enum D7Error {
+    /// the requested bundle or entry does not exist
+    NotFound(String),
+    /// the database or filesystem contains inconsistent or invalid data
+    Corrupt(String),
+    /// the store was created by a newer or older version and cannot be used as is
+    Outdated(String),
+    /// a bundle or user input could not be parsed
+    Parse(String),
+    /// the bundle does not fit into the quota or the MTU
+    Full(String),
+    /// the bundle is not in a state that allows the requested lifecycle step
+    IllegalTransition(String),
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+}
+
+pub type Result<T, E = D7Error> = std::result::Result<T, E>;
+
+impl fmt::Display for D7Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            D7Error::NotFound(what) => write!(f, "not found: {}", what),
+            D7Error::Corrupt(what) => write!(f, "corrupt store: {}", what),
+            D7Error::Outdated(what) => write!(f, "version mismatch: {}", what),
+            D7Error::Parse(what) => write!(f, "parse error: {}", what),
+            D7Error::Full(what) => write!(f, "store is full: {}", what),
+            D7Error::IllegalTransition(what) => write!(f, "illegal transition: {}", what),
+            D7Error::Io(err) => write!(f, "io error: {}", err),
+            D7Error::Sqlite(err) => write!(f, "database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for D7Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            D7Error::Io(err) => Some(err),
+            D7Error::Sqlite(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for D7Error {
+    fn from(err: std::io::Error) -> Self {
+        D7Error::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for D7Error {
+    fn from(err: rusqlite::Error) -> Self {
+        D7Error::Sqlite(err)
+    }
+}
+
+impl From<bp7::error::Error> for D7Error {
+    fn from(err: bp7::error::Error) -> Self {
+        D7Error::Parse(err.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for D7Error {
+    fn from(err: std::num::ParseIntError) -> Self {
+        D7Error::Parse(err.to_string())
+    }
+}
+
+impl From<walkdir::Error> for D7Error {
+    fn from(err: walkdir::Error) -> Self {
+        D7Error::Io(err.into())
+    }
+}
@@ -0,0 +1,241 @@
+//! Helpers for fragmented bundles.
+//!
+//! Fragments share the id of the original bundle extended by their fragment
+//! offset, see [`bp7::Bundle::id`], so they are stored as separate entries.
+use bp7::canonical::PAYLOAD_BLOCK;
+use bp7::flags::{BlockControlFlags, BundleControlFlags};
+use bp7::Bundle;
+
+use crate::error::{D7Error, Result};
+
+/// Returns the id of the original bundle a fragment belongs to.
+///
+/// For bundles that are not fragmented this is the bundle id.
+pub fn parent_bid(bndl: &Bundle) -> String {
+    format!(
+        "{}-{}-{}",
+        bndl.primary.source,
+        bndl.primary.creation_timestamp.dtntime(),
+        bndl.primary.creation_timestamp.seqno()
+    )
+}
+
+/// Returns true if the fragments given as `(offset, length)` cover the whole payload.
+pub fn is_complete(fragments: &[(u64, u64)], total_data_length: u64) -> bool {
+    let mut fragments = fragments.to_vec();
+    fragments.sort_unstable();
+    let mut covered = 0;
+    for (offset, length) in fragments {
+        if offset > covered {
+            return false;
+        }
+        covered = covered.max(offset + length);
+    }
+    covered >= total_data_length
+}
+
+/// Encoded size of a fragment of `bndl` without payload data.
+///
+/// Offsets are set to their maximum so the size does not grow for later fragments.
+fn fragment_overhead(bndl: &Bundle, total: u64, first: bool) -> u64 {
+    let mut template = bndl.clone();
+    template.primary.bundle_control_flags |= BundleControlFlags::BUNDLE_IS_FRAGMENT.bits();
+    template.primary.fragmentation_offset = total;
+    template.primary.total_data_length = total;
+    if !first {
+        template.canonicals.retain(|b| {
+            b.block_type == PAYLOAD_BLOCK
+                || b.block_control_flags & BlockControlFlags::BLOCK_REPLICATE.bits() != 0
+        });
+    }
+    template.set_payload(Vec::new());
+    // the byte string header of the payload grows up to 9 bytes
+    template.to_cbor().len() as u64 + 8
+}
+
+/// Splits a bundle into fragments whose encoding is at most `mtu` bytes.
+///
+/// The first fragment carries all extension blocks, the others only the ones
+/// flagged for replication. Fragments can be fragmented again, offsets always
+/// refer to the payload of the original bundle.
+pub fn fragment(bndl: &Bundle, mtu: u64) -> Result<Vec<Bundle>> {
+    let is_fragment = bndl.primary.has_fragmentation();
+    let data = bndl.payload().cloned().unwrap_or_default();
+    let (base, total) = if is_fragment {
+        (
+            bndl.primary.fragmentation_offset,
+            bndl.primary.total_data_length,
+        )
+    } else {
+        (0, data.len() as u64)
+    };
+    if bndl.primary.bundle_control_flags & BundleControlFlags::BUNDLE_MUST_NOT_FRAGMENTED.bits()
+        != 0
+    {
+        return Err(D7Error::Full(format!(
+            "{} exceeds {} bytes but must not be fragmented",
+            bndl.id(),
+            mtu
+        )));
+    }
+    let mut fragments = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() || fragments.is_empty() {
+        let first = fragments.is_empty() && base == 0;
+        let overhead = fragment_overhead(bndl, total, first);
+        if overhead >= mtu {
+            return Err(D7Error::Full(format!(
+                "fragments of {} need more than {} bytes",
+                bndl.id(),
+                mtu
+            )));
+        }
+        let end = (offset + (mtu - overhead) as usize).min(data.len());
+        let mut fragment = bndl.clone();
+        fragment.primary.bundle_control_flags |= BundleControlFlags::BUNDLE_IS_FRAGMENT.bits();
+        fragment.primary.fragmentation_offset = base + offset as u64;
+        fragment.primary.total_data_length = total;
+        if !first {
+            fragment.canonicals.retain(|b| {
+                b.block_type == PAYLOAD_BLOCK
+                    || b.block_control_flags & BlockControlFlags::BLOCK_REPLICATE.bits() != 0
+            });
+        }
+        fragment.set_payload(data[offset..end].to_vec());
+        fragments.push(fragment);
+        offset = end;
+    }
+    Ok(fragments)
+}
+
+/// Rebuilds the original bundle from all of its fragments.
+///
+/// Primary and extension blocks are taken from the fragment with offset 0,
+/// overlapping fragments are allowed.
+pub fn reassemble(mut fragments: Vec<Bundle>) -> Result<Bundle> {
+    fragments.sort_by_key(|f| f.primary.fragmentation_offset);
+    let first = fragments
+        .first()
+        .ok_or_else(|| D7Error::NotFound("fragments".into()))?;
+    let parent = parent_bid(first);
+    let total = first.primary.total_data_length;
+    let mut parts = Vec::new();
+    for fragment in &fragments {
+        if !fragment.primary.has_fragmentation()
+            || parent_bid(fragment) != parent
+            || fragment.primary.total_data_length != total
+        {
+            return Err(D7Error::Corrupt(format!(
+                "{} is no fragment of {}",
+                fragment.id(),
+                parent
+            )));
+        }
+        let length = fragment.payload().map_or(0, |p| p.len()) as u64;
+        parts.push((fragment.primary.fragmentation_offset, length));
+    }
+    if !is_complete(&parts, total) {
+        return Err(D7Error::NotFound(format!(
+            "missing fragments of {}",
+            parent
+        )));
+    }
+
+    let mut payload = vec![0; total as usize];
+    for fragment in &fragments {
+        let offset = fragment.primary.fragmentation_offset as usize;
+        if offset >= payload.len() {
+            continue;
+        }
+        let data = fragment.payload().map_or(&[][..], |p| p.as_slice());
+        let end = (offset + data.len()).min(payload.len());
+        payload[offset..end].copy_from_slice(&data[..end - offset]);
+    }
+
+    let mut bndl = fragments.swap_remove(0);
+    bndl.primary.bundle_control_flags &= !BundleControlFlags::BUNDLE_IS_FRAGMENT.bits();
+    bndl.primary.fragmentation_offset = 0;
+    bndl.primary.total_data_length = 0;
+    bndl.set_payload(payload);
+    Ok(bndl)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Bundle with a payload of `len` bytes that may be fragmented.
+    pub(crate) fn big_bundle(len: usize) -> Bundle {
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        bndl.primary.bundle_control_flags &= !BundleControlFlags::BUNDLE_MUST_NOT_FRAGMENTED.bits();
+        bndl.set_payload((0..len).map(|i| i as u8).collect());
+        bndl
+    }
+
+    /// Splits the payload into fragments of `size` bytes.
+    pub(crate) fn split(bndl: &Bundle, size: usize) -> Vec<Bundle> {
+        let payload = bndl.payload().unwrap().clone();
+        payload
+            .chunks(size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut fragment = bndl.clone();
+                fragment.primary.bundle_control_flags |=
+                    BundleControlFlags::BUNDLE_IS_FRAGMENT.bits();
+                fragment.primary.fragmentation_offset = (i * size) as u64;
+                fragment.primary.total_data_length = payload.len() as u64;
+                fragment.set_payload(chunk.to_vec());
+                fragment
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reassemble_test() {
+        assert!(is_complete(&[(50, 50), (0, 60)], 100));
+        assert!(!is_complete(&[(0, 40), (50, 50)], 100));
+        assert!(!is_complete(&[], 1));
+
+        let mut bndl = big_bundle(100);
+        let mut fragments = split(&bndl, 30);
+        assert_eq!(fragments.len(), 4);
+        assert_eq!(parent_bid(&fragments[2]), bndl.id());
+        assert_ne!(fragments[0].id(), fragments[1].id());
+
+        fragments.reverse();
+        let mut reassembled = reassemble(fragments.clone()).unwrap();
+        assert_eq!(reassembled.id(), bndl.id());
+        assert_eq!(reassembled.to_cbor(), bndl.to_cbor());
+
+        fragments.remove(1);
+        assert!(matches!(
+            reassemble(fragments.clone()),
+            Err(D7Error::NotFound(_))
+        ));
+        fragments.push(split(&big_bundle(100), 30).remove(0));
+        assert!(matches!(reassemble(fragments), Err(D7Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn fragment_test() {
+        let mut bndl = big_bundle(1000);
+        let mtu = 300;
+        let mut fragments = fragment(&bndl, mtu).unwrap();
+        assert!(fragments.len() > 3);
+        for f in fragments.iter_mut() {
+            assert!(f.to_cbor().len() as u64 <= mtu);
+            assert_eq!(parent_bid(f), bndl.id());
+        }
+
+        // fragmenting a fragment keeps the offsets of the original
+        let mut refragmented = fragment(&fragments[1], 150).unwrap();
+        assert!(refragmented.len() > 1);
+        refragmented.extend(fragments.drain(..1));
+        refragmented.extend(fragments.drain(1..));
+        assert_eq!(reassemble(refragmented).unwrap().to_cbor(), bndl.to_cbor());
+
+        assert!(matches!(fragment(&bndl, 20), Err(D7Error::Full(_))));
+        let fixed = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        assert!(matches!(fragment(&fixed, 1000), Err(D7Error::Full(_))));
+    }
+}
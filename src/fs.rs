@@ -1,24 +1,157 @@
-use anyhow::{bail, Result};
+use crate::error::{D7Error, Result};
 use bp7::Bundle;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use sanitize_filename_reader_friendly::sanitize;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 use std::{convert::TryInto, fs};
 use walkdir::{DirEntry, WalkDir};
 
 use crate::db::BundleEntry;
 
+/// Encodes a bundle ID as the base name of its bundle file.
+///
+/// Lowercase ASCII letters, digits, `-`, `.` and `_` are kept as they are,
+/// every other byte of the UTF-8 representation is written as `%XX` with
+/// uppercase hex digits. The encoding is lossless and stays unique on
+/// case-insensitive file systems, e.g., `dtn://node1/sms-1-0` becomes
+/// `dtn%3A%2F%2Fnode1%2Fsms-1-0`.
+pub fn encode_bid(bid: &str) -> String {
+    let mut res = String::with_capacity(bid.len());
+    for b in bid.bytes() {
+        match b {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' => res.push(b as char),
+            _ => res.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    res
+}
+
+/// Reconstructs the bundle ID from the base name of a bundle file.
+///
+/// Returns `None` if the name was not produced by [`encode_bid`].
+pub fn decode_bid(filebase: &str) -> Option<String> {
+    let mut buf = Vec::with_capacity(filebase.len());
+    let mut bytes = filebase.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' => buf.push(b),
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                if hex.chars().any(|c| c.is_ascii_lowercase()) {
+                    return None;
+                }
+                buf.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            _ => return None,
+        }
+    }
+    let bid = String::from_utf8(buf).ok()?;
+    // every bundle ID starts with an URI scheme
+    if bid.contains(':') {
+        Some(bid)
+    } else {
+        None
+    }
+}
+
+/// Counter to give each temporary store of this process a unique directory.
+static NEXT_TEMP_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// Counter to give concurrent writes of this process unique temporary files.
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// Flushes the directory entry of renamed or created files to disk.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories cannot be opened for syncing on this platform.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Temporary directory that is removed when dropped.
+#[derive(Debug)]
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty directory unique to this process.
+    pub(crate) fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "d7sneakers-{}-{}",
+            std::process::id(),
+            NEXT_TEMP_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            warn!("could not remove {}: {}", self.0.display(), err);
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct D7sFs {
     base: String,
+    /// set for temporary stores, shared by all clones
+    temp: Option<Arc<TempDir>>,
+    /// database used to look up bundle files without searching the filesystem
+    index: Option<crate::D7DB>,
 }
 
 impl D7sFs {
     pub fn open(base: &str) -> Result<Self> {
-        let me = Self { base: base.into() };
+        let me = Self {
+            base: base.into(),
+            temp: None,
+            index: None,
+        };
+        me.setup()?;
+        Ok(me)
+    }
+    /// Opens a store in a new temporary directory.
+    ///
+    /// The directory is removed when the last clone is dropped.
+    pub fn open_temp() -> Result<Self> {
+        let dir = TempDir::new()?;
+        let me = Self {
+            base: dir.path().to_string_lossy().into(),
+            temp: Some(Arc::new(dir)),
+            index: None,
+        };
         me.setup()?;
         Ok(me)
     }
+    /// Uses the paths stored in `db` to locate bundle files.
+    ///
+    /// With an index, [`D7sFs::find_file_by_bid`] and the methods built upon it
+    /// no longer walk the whole store. Bundles unknown to the index are
+    /// considered missing, outdated paths are searched for and corrected.
+    pub fn with_index(mut self, db: crate::D7DB) -> Self {
+        self.index = Some(db);
+        self
+    }
+    /// returns true for stores opened with [`D7sFs::open_temp`]
+    pub fn is_temporary(&self) -> bool {
+        self.temp.is_some()
+    }
     fn setup(&self) -> Result<()> {
         let basepath = Path::new(&self.base);
         fs::create_dir_all(basepath)?;
@@ -26,16 +159,30 @@ impl D7sFs {
         fs::create_dir_all(self.path_single())?;
         fs::create_dir_all(self.path_administrative())?;
         fs::create_dir_all(self.path_group())?;
+        fs::create_dir_all(self.path_ipn())?;
+        fs::create_dir_all(self.path_none())?;
+        fs::create_dir_all(self.path_tmp())?;
+        self.remove_partial_files()?;
 
         let version_file = basepath.join("version.txt");
         if version_file.exists() {
-            let version: u32 = fs::read_to_string(version_file)?.parse()?;
+            let version: u32 = fs::read_to_string(version_file)?
+                .trim()
+                .parse()
+                .map_err(|_| D7Error::Corrupt("invalid version.txt".into()))?;
             if version < crate::D7S_VERSION {
-                info!("old filesystem structure detected, upgrade needed");
-                unimplemented!();
+                error!("old filesystem structure detected, upgrade needed");
+                return Err(D7Error::Outdated(format!(
+                    "filesystem structure version {} needs an upgrade",
+                    version
+                )));
             } else if version > crate::D7S_VERSION {
                 error!("filesystem structure is newer, upgrade program to newest version");
-                bail!("outdated program version");
+                return Err(D7Error::Outdated(format!(
+                    "filesystem structure version {} is newer than supported version {}",
+                    version,
+                    crate::D7S_VERSION
+                )));
             }
         }
         let version_file = basepath.join("version.txt");
@@ -43,6 +190,17 @@ impl D7sFs {
 
         Ok(())
     }
+    /// Removes temporary files of writes that never completed, e.g., due to a power loss.
+    fn remove_partial_files(&self) -> Result<()> {
+        for entry in fs::read_dir(self.path_tmp())? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                warn!("removing partially written file {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
     pub fn path_single(&self) -> PathBuf {
         let basepath = Path::new(&self.base);
         basepath.join("single")
@@ -55,6 +213,19 @@ impl D7sFs {
         let basepath = Path::new(&self.base);
         basepath.join("group")
     }
+    pub fn path_none(&self) -> PathBuf {
+        let basepath = Path::new(&self.base);
+        basepath.join("none")
+    }
+    /// Directory for files that are being written, it must be on the same file system as the bundles.
+    pub fn path_tmp(&self) -> PathBuf {
+        let basepath = Path::new(&self.base);
+        basepath.join("tmp")
+    }
+    pub fn path_ipn(&self) -> PathBuf {
+        let basepath = Path::new(&self.base);
+        basepath.join("ipn")
+    }
     pub fn path_for_bundle(&self, bndl: &Bundle) -> PathBuf {
         let dst = sanitize(
             &bndl
@@ -74,65 +245,104 @@ impl D7sFs {
                         self.path_single().join(&dst)
                     }
                 }
-                bp7::EndpointID::DtnNone(_, _) => {
-                    unimplemented!()
-                }
-                bp7::EndpointID::Ipn(_, _addr) => {
-                    unimplemented!()
+                bp7::EndpointID::DtnNone(_, _) => self.path_none(),
+                bp7::EndpointID::Ipn(_, addr) => {
+                    self.path_ipn().join(addr.node_number().to_string())
                 }
             }
         }
     }
 
     pub fn path_for_bundle_with_filename(&self, bndl: &Bundle) -> PathBuf {
-        let filename = format!("{}.bundle", sanitize(&bndl.id()));
+        let filename = format!("{}.bundle", encode_bid(&bndl.id()));
         self.path_for_bundle(bndl).join(&filename)
     }
     pub fn exists(&self, bndl: &Bundle) -> bool {
         self.path_for_bundle_with_filename(bndl).exists()
     }
+    /// Writes `data` to a temporary file and moves it to `dest_path` once it is on disk.
+    ///
+    /// Readers either see the complete old file or the complete new file.
+    fn write_atomic(&self, dest_path: &Path, data: &[u8]) -> Result<()> {
+        let tmp_path = self.path_tmp().join(format!(
+            "{}.{}.{}.tmp",
+            dest_path.file_stem().unwrap_or_default().to_string_lossy(),
+            std::process::id(),
+            NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let res = fs::File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, dest_path));
+        if let Err(err) = res {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
+        if let Some(dir) = dest_path.parent() {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
     pub fn save_bundle(&self, bndl: &mut Bundle) -> Result<(u64, String)> {
         let bid = bndl.id();
-        let filename = format!("{}.bundle", sanitize(&bid));
+        let filename = format!("{}.bundle", encode_bid(&bid));
         let dest_path = self.path_for_bundle(bndl);
 
         fs::create_dir_all(&dest_path)?;
         let dest_path = dest_path.join(&filename);
-        if dest_path.exists() {
-            debug!("File {} already exists, skipping", filename);
-        } else {
-            fs::write(&dest_path, bndl.to_cbor())?;
-            debug!("saved {} to {}", bid, dest_path.to_string_lossy());
+        let data = bndl.to_cbor();
+        match fs::metadata(&dest_path) {
+            Ok(meta) if meta.len() == data.len() as u64 => {
+                debug!("File {} already exists, skipping", filename);
+            }
+            Ok(_) => {
+                warn!("replacing incomplete file {}", dest_path.display());
+                self.write_atomic(&dest_path, &data)?;
+            }
+            Err(_) => {
+                self.write_atomic(&dest_path, &data)?;
+                debug!("saved {} to {}", bid, dest_path.to_string_lossy());
+            }
         }
-        //info!("filename {}", filename);
-        Ok((
-            fs::metadata(&dest_path)?.len(),
-            dest_path.to_string_lossy().into(),
-        ))
+        Ok((data.len() as u64, dest_path.to_string_lossy().into()))
     }
     pub fn remove_bundle(&self, bid: &str) -> Result<()> {
-        if let Some(filename) = self.find_file_by_bid(bid) {
+        if let Some(filename) = self.find_file_by_bid(bid)? {
             fs::remove_file(filename)?;
         } else {
-            bail!("bundle ID not found");
+            return Err(D7Error::NotFound(bid.to_owned()));
         }
         Ok(())
     }
-    pub fn find_file_by_bid(&self, bid: &str) -> Option<PathBuf> {
-        let target = format!("{}.bundle", sanitize(bid));
-        for entry in WalkDir::new(&self.base)
+    pub fn find_file_by_bid(&self, bid: &str) -> Result<Option<PathBuf>> {
+        let target = format!("{}.bundle", encode_bid(bid));
+        if let Some(db) = &self.index {
+            // unknown bundles have no row, deleted ones keep their row without path
+            let path = match db.path_for_bundle(bid)? {
+                Some(path) => PathBuf::from(path),
+                None => return Ok(None),
+            };
+            if path.starts_with(&self.base)
+                && path.file_name() == Some(target.as_ref())
+                && path.exists()
+            {
+                return Ok(Some(path));
+            }
+            debug!("outdated path for {} in index, searching filesystem", bid);
+        }
+        let path = WalkDir::new(&self.base)
             .into_iter()
             .filter_map(|e| e.ok())
-            .filter(|f| f.file_name().to_str().unwrap_or_default() == target)
-        {
-            //let filename = entry.file_name().to_str()?;
-            //if filename == format!("{}.bundle", sanitize(bid)) {
-            return Some(entry.into_path());
-            //}
+            .find(|f| f.file_name().to_str().unwrap_or_default() == target)
+            .map(|entry| entry.into_path());
+        if let (Some(db), Some(path)) = (&self.index, &path) {
+            db.set_path(bid, Some(path.to_string_lossy().to_string()))?;
         }
-        None
+        Ok(path)
     }
-    pub fn all_bids(&self) -> Vec<String> {
+    pub fn all_bids(&self) -> Result<Vec<String>> {
         let mut bids = Vec::new();
         for entry in WalkDir::new(&self.base)
             .into_iter()
@@ -144,34 +354,26 @@ impl D7sFs {
                     .ends_with(".bundle")
             })
         {
-            let filename = entry
+            let filebase = entry
                 .file_name()
                 .to_str()
-                .unwrap()
-                .rsplit('.')
-                .collect::<Vec<&str>>()[1]
-                .to_string();
-            if filename.starts_with("dtn_") {
-                let bid = if filename.starts_with("dtn_none") {
-                    filename.replacen('_', ":", 1)
-                } else {
-                    filename.replacen('_', "://", 1)
-                };
-                let bid = bid.replacen('_', "/", 1);
+                .unwrap_or_default()
+                .trim_end_matches(".bundle");
+            if let Some(bid) = decode_bid(filebase) {
                 bids.push(bid);
             } else {
-                unimplemented!("only dtn bundle scheme support at the moment!");
+                warn!("unknown bundle file naming: {}", entry.path().display());
             }
         }
-        bids
+        Ok(bids)
     }
     pub fn get_bundle(&self, bid: &str) -> Result<Bundle> {
-        if let Some(filename) = self.find_file_by_bid(bid) {
+        if let Some(filename) = self.find_file_by_bid(bid)? {
             let buffer = fs::read(filename)?;
             let bndl: Bundle = buffer.try_into()?;
             Ok(bndl)
         } else {
-            bail!("bundle ID not found");
+            Err(D7Error::NotFound(bid.to_owned()))
         }
     }
     fn check_file_from_store(
@@ -179,15 +381,13 @@ impl D7sFs {
         entry: DirEntry,
         db: &crate::D7DB,
     ) -> Result<Option<(String, BundleEntry)>> {
-        let (filebase, _extension) = entry
+        let filebase = entry
             .file_name()
             .to_str()
-            .unwrap()
-            .rsplit_once('.')
-            .unwrap();
-        let res = if filebase.starts_with("dtn") {
-            let bid = filebase.replace('_', "/").replacen("dtn", "dtn:/", 1);
-            let is_in_db = db.exists(&bid);
+            .unwrap_or_default()
+            .trim_end_matches(".bundle");
+        let res = if let Some(bid) = decode_bid(filebase) {
+            let is_in_db = db.exists(&bid)?;
             debug!("{} in db: {}", entry.path().display(), is_in_db);
             if !is_in_db {
                 let buf = std::fs::read(entry.path())?;
@@ -242,3 +442,46 @@ impl D7sFs {
         Ok((bndl, bundle_size, path))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_bid, encode_bid, D7sFs};
+
+    #[test]
+    fn bid_codec_test() {
+        for bid in [
+            "dtn://node1/sms-683289437000-0",
+            "dtn://Node_1/a-b/~c_d-683289437000-12",
+            "dtn://node1/-683289437000-0-42",
+            "dtn:none-683289437000-3",
+            "ipn:23.42-683289437000-1",
+            "dtn://nöde%20x/ü-0-0",
+        ] {
+            let encoded = encode_bid(bid);
+            assert!(!encoded.contains(['/', ':', '\\']));
+            assert_eq!(decode_bid(&encoded).as_deref(), Some(bid));
+        }
+        assert_ne!(encode_bid("dtn://a/x-0-0"), encode_bid("dtn://A/x-0-0"));
+        assert_eq!(decode_bid("dtn_node1_sms-683289437000-0"), None);
+        assert_eq!(decode_bid("dtn%3a%2F%2Fnode1%2Fsms-0-0"), None);
+        assert_eq!(decode_bid("dtn%3A%2"), None);
+    }
+
+    #[test]
+    fn partial_write_test() {
+        let store = D7sFs::open_temp().unwrap();
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        let (size, path) = store.save_bundle(&mut bndl).unwrap();
+
+        // leftovers of an interrupted write
+        std::fs::write(&path, &bndl.to_cbor()[..5]).unwrap();
+        let leftover = store.path_tmp().join("x.1.0.tmp");
+        std::fs::write(&leftover, b"ABC").unwrap();
+
+        let reopened = D7sFs::open(&store.path_tmp().parent().unwrap().to_string_lossy()).unwrap();
+        assert!(!leftover.exists());
+        assert_eq!(reopened.save_bundle(&mut bndl).unwrap().0, size);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        assert_eq!(reopened.get_bundle(&bndl.id()).unwrap().id(), bndl.id());
+    }
+}
@@ -1,6 +1,10 @@
 #![forbid(unsafe_code)]
-use anyhow::Result;
-use bp7::Bundle;
+use bp7::administrative_record::{
+    StatusInformationPos, StatusReport, StatusReportReason, DELETED_BUNDLE, DELIVERED_BUNDLE,
+    DEPLETED_STORAGE, FORWARDED_BUNDLE, LIFETIME_EXPIRED, NO_INFORMATION, RECEIVED_BUNDLE,
+};
+use bp7::canonical::BUNDLE_AGE_BLOCK;
+use bp7::{Bundle, EndpointID};
 use db::BundleEntry;
 use log::debug;
 use log::warn;
@@ -8,99 +12,976 @@ use walkdir::DirEntry;
 use walkdir::WalkDir;
 
 mod db;
+mod error;
+mod fragment;
 mod fs;
+mod lifecycle;
+mod migrate;
+mod quota;
+mod send;
+mod status;
+mod summary;
 
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::path::Path;
 
+pub use db::BundleFilter;
 pub use db::Constraints;
+pub use db::Delivery;
+pub use db::Intent;
 pub use db::D7DB;
-pub use fs::D7sFs;
+pub use db::SCHEMA_VERSION;
+pub use error::{D7Error, Result};
+pub use fragment::parent_bid;
+pub use fs::{decode_bid, encode_bid, D7sFs};
+pub use lifecycle::Transition;
+pub use migrate::UpgradeReport;
+pub use quota::{
+    EvictionCandidate, EvictionPolicy, KeepLocal, LargestFirst, OldestFirst, Quota, SoonestExpiry,
+};
+pub use send::SendOptions;
+pub use summary::SummaryVector;
 
-pub const D7S_VERSION: u32 = 1;
+pub const D7S_VERSION: u32 = 2;
 
 use log::info;
 
+/// Result of a garbage collection run.
+#[derive(Debug, Clone, Default)]
+pub struct GcReport {
+    /// nothing was removed if set
+    pub dry_run: bool,
+    /// bundle ids whose lifetime is exceeded
+    pub expired: Vec<String>,
+    /// total size of the expired bundles in bytes
+    pub freed: u64,
+}
+
+/// Directions in which [`SneakerWorld::sync_with`] copies missing bundles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// copy bundles only known to the other store into this one
+    Pull,
+    /// copy bundles only known to this store into the other one
+    Push,
+    Both,
+}
+
+/// Result of a synchronisation between two stores.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    /// bundle ids copied from the other store
+    pub pulled: Vec<String>,
+    /// bundle ids copied to the other store
+    pub pushed: Vec<String>,
+    /// bundle ids that did not fit into the quota of the receiving store
+    pub skipped: Vec<String>,
+}
+
+/// Encodings of a stored bundle for [`SneakerWorld::get_encoded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleFormat {
+    /// only the data of the payload block
+    Payload,
+    /// the bundle as stored
+    Cbor,
+    /// the stored bundle as hex string, can be added again with `add --hex`
+    Hex,
+    Json,
+}
+
+impl std::str::FromStr for BundleFormat {
+    type Err = D7Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "payload" => Ok(BundleFormat::Payload),
+            "cbor" => Ok(BundleFormat::Cbor),
+            "hex" => Ok(BundleFormat::Hex),
+            "json" => Ok(BundleFormat::Json),
+            _ => Err(D7Error::Parse(format!("unknown bundle format {}", s))),
+        }
+    }
+}
+
+/// Constraints that only apply to the node storing the bundle and are not carried over.
+const NODE_LOCAL_CONSTRAINTS: Constraints = Constraints::LOCAL_ENDPOINT;
+
 #[derive(Debug, Clone)]
 pub struct SneakerWorld {
     pub db: D7DB,
     pub fs: D7sFs,
+    quota: Quota,
+    mtu: Option<u64>,
+    node_id: Option<EndpointID>,
 }
 
 impl SneakerWorld {
+    /// Opens the store at `basepath`, upgrading an older filesystem structure first.
     pub fn open(basepath: &str) -> Result<Self> {
+        let report = migrate::upgrade(basepath, false)?;
+        if report.from != report.to {
+            info!(
+                "upgraded store from version {} to {}",
+                report.from, report.to
+            );
+        }
         let db_file = Path::new(basepath)
             .join("db.sqlite3")
-            .as_os_str()
-            .to_str()
-            .unwrap()
-            .to_owned();
+            .to_string_lossy()
+            .to_string();
         let file_path = Path::new(basepath)
             .join("files")
-            .as_os_str()
-            .to_str()
-            .unwrap()
-            .to_owned();
+            .to_string_lossy()
+            .to_string();
+        let db = db::D7DB::open(&db_file)?;
+        let world = Self {
+            fs: D7sFs::open(&file_path)?.with_index(db.clone()),
+            db,
+            quota: Quota::unlimited(),
+            mtu: None,
+            node_id: None,
+        };
+        world.repair()?;
+        Ok(world)
+    }
+    /// Opens a store without persistent state.
+    ///
+    /// The database lives in memory and bundles are kept in a temporary
+    /// directory, both are discarded when the last clone is dropped.
+    pub fn open_in_memory() -> Result<Self> {
+        let db = D7DB::open_in_memory()?;
         Ok(Self {
-            db: db::D7DB::open(&db_file)?,
-            fs: D7sFs::open(&file_path)?,
+            fs: D7sFs::open_temp()?.with_index(db.clone()),
+            db,
+            quota: Quota::unlimited(),
+            mtu: None,
+            node_id: None,
         })
     }
+    /// Limits the size of the store, `push` and `import_dir` evict bundles to stay within the quota.
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+    /// Sets the endpoint of this node, status reports are only generated if it is set.
+    pub fn with_node_id(mut self, node_id: EndpointID) -> Self {
+        self.node_id = Some(node_id);
+        self
+    }
+    pub fn node_id(&self) -> Option<&EndpointID> {
+        self.node_id.as_ref()
+    }
+    /// Evicts bundles until `bytes` and `bundles` more fit into the store.
+    ///
+    /// `pending` bytes and bundles are about to be inserted and count as used.
+    /// Nothing is evicted if not enough space can be freed, without `evict`
+    /// only the free space is checked.
+    fn make_room(&self, bytes: u64, bundles: u64, pending: (u64, u64), evict: bool) -> Result<()> {
+        if self.quota.is_unlimited() {
+            return Ok(());
+        }
+        loop {
+            let used_bytes = self.db.total_size()? + pending.0;
+            let used_bundles = self.db.stored_len()? as u64 + pending.1;
+            let candidates = if evict {
+                self.db.eviction_candidates()?
+            } else {
+                Vec::new()
+            };
+            let plan = self
+                .quota
+                .plan(used_bytes, used_bundles, bytes, bundles, candidates)
+                .ok_or_else(|| {
+                    D7Error::Full(format!(
+                        "{} bytes in {} bundles used, {} bytes requested",
+                        used_bytes, used_bundles, bytes
+                    ))
+                })?;
+            if plan.is_empty() {
+                return Ok(());
+            }
+            for candidate in plan {
+                info!(
+                    "evicting {} ({} bytes) to stay within quota",
+                    candidate.bid, candidate.size
+                );
+                self.remove_with_reason(&candidate.bid, DEPLETED_STORAGE)?;
+            }
+            // deletion reports may have taken some of the freed space
+        }
+    }
+    /// Upgrades the store at `basepath` without opening it.
+    ///
+    /// In dry-run mode nothing is changed and the report lists the planned actions.
+    pub fn upgrade(basepath: &str, dry_run: bool) -> Result<UpgradeReport> {
+        migrate::upgrade(basepath, dry_run)
+    }
+    /// Reverts the last upgrade of the store at `basepath`.
+    pub fn rollback_upgrade(basepath: &str) -> Result<()> {
+        migrate::rollback(basepath)
+    }
     pub fn sync(&self) -> Result<()> {
         self.fs.sync_to_db(&self.db)?;
         self.db.sync_with_fs(&self.fs)
     }
+    /// Completes filesystem changes that were interrupted by a crash.
+    ///
+    /// Written bundles are added to the database, bundles whose row was
+    /// already deleted get their file removed. Returns the number of repairs.
+    pub fn repair(&self) -> Result<usize> {
+        let intents = self.db.intents()?;
+        for (bid, intent, path) in &intents {
+            match intent {
+                Intent::Push if self.db.exists(bid)? => self.db.clear_intent(bid)?,
+                Intent::Push => {
+                    let buf = path.as_ref().and_then(|path| std::fs::read(path).ok());
+                    match buf.map(Bundle::try_from) {
+                        Some(Ok(mut bndl)) if bndl.id() == *bid => {
+                            info!("repair: completing interrupted push of {}", bid);
+                            let size = bndl.to_cbor().len() as u64;
+                            self.db.insert(&bndl, size, path.clone())?;
+                        }
+                        _ => {
+                            info!("repair: discarding incomplete push of {}", bid);
+                            self.discard_file(bid, path.as_deref())?;
+                        }
+                    }
+                }
+                Intent::Remove => {
+                    info!("repair: completing interrupted removal of {}", bid);
+                    // deleted bundles keep their row, only the file is discarded
+                    if self.db.exists(bid)? && !self.is_deleted(bid)? {
+                        self.db.delete_logged(bid, path.as_deref())?;
+                    }
+                    self.discard_file(bid, path.as_deref())?;
+                }
+            }
+        }
+        Ok(intents.len())
+    }
+    /// Removes the file of an unfinished push or removal and clears its intent.
+    fn discard_file(&self, bid: &str, path: Option<&str>) -> Result<()> {
+        if let Some(path) = path {
+            remove_file_if_exists(Path::new(path))?;
+        }
+        self.db.clear_intent(bid)
+    }
+    /// Stores the bundle, either both file and database entry are added or neither.
+    ///
+    /// Status reports carried by new administrative records are processed and
+    /// a reception report is generated if the bundle requests one.
     pub fn push(&self, bndl: &mut Bundle) -> Result<()> {
-        let (bundle_size, path) = self.fs.save_bundle(bndl)?;
-        self.db.insert(bndl, bundle_size, Some(path))
+        if self.store(bndl, true)? {
+            self.received(bndl)?;
+        }
+        Ok(())
     }
+    /// Stores the bundle, returns false if it was known already.
+    ///
+    /// Without `evict` no other bundle is removed to make room for it.
+    fn store(&self, bndl: &mut Bundle, evict: bool) -> Result<bool> {
+        let bid = bndl.id();
+        if self.db.exists(&bid)? {
+            // deleted bundles keep their id but never get their file back
+            if !self
+                .db
+                .get_constraints(&bid)?
+                .contains(Constraints::DELETED)
+            {
+                self.fs.save_bundle(bndl)?;
+            }
+            return Ok(false);
+        }
+        self.make_room(bndl.to_cbor().len() as u64, 1, (0, 0), evict)?;
+        let path = self.fs.path_for_bundle_with_filename(bndl);
+        let path = path.to_string_lossy();
+        self.db.log_intent(&bid, Intent::Push, Some(&path))?;
+        let res = self
+            .fs
+            .save_bundle(bndl)
+            .and_then(|(bundle_size, path)| self.db.insert(bndl, bundle_size, Some(path)));
+        if let Err(err) = res {
+            self.discard_file(&bid, Some(&path))?;
+            return Err(err);
+        }
+        if bndl.primary.has_fragmentation() {
+            self.track_fragment(&bid, &BundleEntry::from(&*bndl))?;
+        }
+        Ok(true)
+    }
+    /// Handles a bundle that was newly added to the store.
+    fn received(&self, bndl: &Bundle) -> Result<()> {
+        if bndl.is_administrative_record() {
+            if let Err(err) = self.process_status_report(bndl) {
+                warn!(
+                    "could not process administrative record {}: {}",
+                    bndl.id(),
+                    err
+                );
+            }
+        } else {
+            self.report_bundle_status(bndl, RECEIVED_BUNDLE, NO_INFORMATION)?;
+            self.dispatch_local(&bndl.id(), bndl.primary.has_fragmentation())?;
+        }
+        Ok(())
+    }
+    /// Marks a new bundle for a registered endpoint as received and local.
+    ///
+    /// Fragments are only delivered once reassembled.
+    fn dispatch_local(&self, bid: &str, is_fragment: bool) -> Result<()> {
+        if !is_fragment && self.db.local_endpoint(bid)?.is_some() {
+            self.receive(bid)?;
+            self.dispatch(bid)?;
+        }
+        Ok(())
+    }
+    /// Marks a received fragment for reassembly unless the original bundle is stored already.
+    fn track_fragment(&self, bid: &str, be: &BundleEntry) -> Result<()> {
+        if let Some(parent) = &be.parent_bid {
+            if !self.db.exists(parent)? {
+                self.db
+                    .add_constraints(bid, Constraints::REASSEMBLY_PENDING)?;
+            }
+        }
+        Ok(())
+    }
+    /// Rebuilds the original bundle once all of its fragments are stored.
+    ///
+    /// The original bundle is added to the store and `REASSEMBLY_PENDING` is
+    /// cleared from its fragments, returns `None` while fragments are missing.
+    pub fn reassemble(&self, parent_bid: &str) -> Result<Option<Bundle>> {
+        let fragments = self.db.fragments_of(parent_bid)?;
+        let first = fragments
+            .first()
+            .ok_or_else(|| D7Error::NotFound(parent_bid.to_owned()))?;
+        let total = self
+            .db
+            .get_bundle_entry(&first.0)?
+            .total_data_length
+            .unwrap_or_default();
+        let parts: Vec<(u64, u64)> = fragments.iter().map(|f| (f.1, f.2)).collect();
+        if !fragment::is_complete(&parts, total) {
+            debug!("{} is still missing fragments", parent_bid);
+            return Ok(None);
+        }
+        let bundles = fragments
+            .iter()
+            .map(|f| self.get_bundle(&f.0))
+            .collect::<Result<Vec<_>>>()?;
+        let mut bndl = fragment::reassemble(bundles)?;
+        if self.store(&mut bndl, true)? {
+            self.dispatch_local(parent_bid, false)?;
+        }
+        for (bid, _, _) in &fragments {
+            self.db
+                .remove_constraints(bid, Constraints::REASSEMBLY_PENDING)?;
+        }
+        info!(
+            "reassembled {} from {} fragments",
+            parent_bid,
+            fragments.len()
+        );
+        Ok(Some(bndl))
+    }
+    /// Reassembles all bundles whose fragments are complete, returns their ids.
+    pub fn reassemble_all(&self) -> Result<Vec<String>> {
+        let mut reassembled = Vec::new();
+        for parent in self.db.pending_reassemblies()? {
+            if self.reassemble(&parent)?.is_some() {
+                reassembled.push(parent);
+            }
+        }
+        Ok(reassembled)
+    }
+    /// Removes the bundle from the database and the filesystem.
+    ///
+    /// The database entry is removed first, if the file cannot be deleted the
+    /// removal is completed by [`SneakerWorld::repair`] on the next open.
     pub fn remove(&self, bid: &str) -> Result<()> {
-        self.fs.remove_bundle(bid)?;
-        self.db.delete(bid)
+        self.remove_with_reason(bid, NO_INFORMATION)
+    }
+    /// Removes the bundle and sends a deletion report if the bundle requests one.
+    fn remove_with_reason(&self, bid: &str, reason: StatusReportReason) -> Result<()> {
+        // deleted bundles have no file, don't search the whole tree for it
+        if self.is_deleted(bid)? {
+            return self.db.delete(bid);
+        }
+        let path = match self.fs.find_file_by_bid(bid)? {
+            Some(path) => path,
+            None => return Err(D7Error::NotFound(bid.to_owned())),
+        };
+        // the bundle is only needed for its report
+        let bndl = match self.node_id {
+            Some(_) => self.fs.get_bundle(bid).ok(),
+            None => None,
+        };
+        let path = path.to_string_lossy();
+        self.db.delete_logged(bid, Some(&path))?;
+        self.discard_file(bid, Some(&path))?;
+        if let Some(bndl) = bndl {
+            self.report_bundle_status(&bndl, DELETED_BUNDLE, reason)?;
+        }
+        Ok(())
+    }
+    fn is_deleted(&self, bid: &str) -> Result<bool> {
+        Ok(self.db.exists(bid)? && self.db.get_constraints(bid)?.contains(Constraints::DELETED))
+    }
+    /// Applies a lifecycle step to the constraints of a stored bundle.
+    fn transition(&self, bid: &str, transition: Transition) -> Result<Constraints> {
+        let constraints = transition.apply(self.db.get_constraints(bid)?)?;
+        self.db.set_constraints(bid, constraints)?;
+        debug!("{} {}: {:?}", transition, bid, constraints);
+        Ok(constraints)
+    }
+    /// Marks a stored bundle as received, it is then pending dispatch.
+    ///
+    /// The reception report is already generated when the bundle is stored.
+    pub fn receive(&self, bid: &str) -> Result<Constraints> {
+        self.transition(bid, Transition::Receive)
+    }
+    /// Decides between local delivery and forwarding of a received bundle.
+    ///
+    /// Bundles destined for the node id of this store or a registered endpoint
+    /// are marked `LOCAL_ENDPOINT`, all others `FORWARD_PENDING`.
+    pub fn dispatch(&self, bid: &str) -> Result<Constraints> {
+        let be = self.db.get_bundle_entry(bid)?;
+        // ipn node numbers are also returned as node names, so the scheme must match
+        let local =
+            self.node_id
+                .as_ref()
+                .is_some_and(|node| match node.scheme_specific_part_ipn() {
+                    Some(addr) => be.dst_node_no == Some(addr.node_number()),
+                    None => {
+                        be.dst_node_no.is_none()
+                            && be.dst_name.is_some()
+                            && be.dst_name == node.node()
+                    }
+                })
+                || self.db.local_endpoint(bid)?.is_some();
+        self.transition(bid, Transition::Dispatch { local })
+    }
+    /// Registers a local endpoint such as `dtn://me/chat`.
+    ///
+    /// Bundles for the endpoint are marked `LOCAL_ENDPOINT` when they are stored,
+    /// stored bundles pending dispatch are dispatched. Returns their ids.
+    pub fn register_endpoint(&self, eid: &EndpointID) -> Result<Vec<String>> {
+        self.db.register_endpoint(eid)?;
+        let eid = eid.to_string();
+        let mut dispatched = Vec::new();
+        for bid in self.db.filter_constraints(Constraints::DISPATCH_PENDING)? {
+            if self.db.local_endpoint(&bid)?.as_ref() == Some(&eid) {
+                self.dispatch(&bid)?;
+                dispatched.push(bid);
+            }
+        }
+        info!("registered endpoint {}", eid);
+        Ok(dispatched)
+    }
+    /// Unregisters a local endpoint, its undelivered bundles are dispatched again.
+    ///
+    /// Without the endpoint they are usually forwarded, returns their ids.
+    pub fn unregister_endpoint(&self, eid: &str) -> Result<Vec<String>> {
+        let pending = self.db.pending_deliveries(eid)?;
+        self.db.unregister_endpoint(eid)?;
+        for bid in &pending {
+            let constraints = self.db.get_constraints(bid)?;
+            self.db.set_constraints(
+                bid,
+                (constraints - Constraints::LOCAL_ENDPOINT) | Constraints::DISPATCH_PENDING,
+            )?;
+            self.dispatch(bid)?;
+        }
+        info!("unregistered endpoint {}", eid);
+        Ok(pending)
+    }
+    /// Returns the ids of the bundles waiting for delivery to a registered endpoint, oldest first.
+    pub fn pending_deliveries(&self, eid: &str) -> Result<Vec<String>> {
+        self.db.pending_deliveries(eid)
+    }
+    /// Returns the bundles waiting for delivery to a registered endpoint and marks them delivered.
+    ///
+    /// The delivery is recorded with `eid` as peer, see [`SneakerWorld::deliver`].
+    pub fn consume(&self, eid: &str) -> Result<Vec<Bundle>> {
+        let mut bundles = Vec::new();
+        for bid in self.db.pending_deliveries(eid)? {
+            let bndl = self.get_bundle(&bid)?;
+            self.deliver(&bid)?;
+            self.db.mark_delivered(&bid, eid, Some("local"))?;
+            bundles.push(bndl);
+        }
+        Ok(bundles)
+    }
+    /// Creates a bundle from `src` to `dst` and adds it to the store, returns its id.
+    ///
+    /// Sequence numbers are counted per source in the database, so they stay
+    /// unique across restarts. The new bundle is received and dispatched like
+    /// an incoming one, but no reception report is generated.
+    pub fn send(
+        &self,
+        src: &EndpointID,
+        dst: &EndpointID,
+        payload: Vec<u8>,
+        options: &SendOptions,
+    ) -> Result<String> {
+        // rejected bundles must not use up a sequence number
+        options.validate()?;
+        let seqno = self.db.next_seqno(&src.to_string())?;
+        let timestamp = bp7::CreationTimestamp::with_time_and_seq(bp7::dtn_time_now(), seqno);
+        let mut bndl = options.build(src, dst, payload, timestamp)?;
+        let bid = bndl.id();
+        self.store(&mut bndl, true)?;
+        self.receive(&bid)?;
+        self.dispatch(&bid)?;
+        info!("created {}", bid);
+        Ok(bid)
+    }
+    /// Records that a bundle pending forwarding was handed to `peer`, see [`SneakerWorld::mark_forwarded`].
+    pub fn forward(&self, bid: &str, peer: &str, cla: Option<&str>) -> Result<Constraints> {
+        let constraints = self.transition(bid, Transition::Forward)?;
+        self.mark_forwarded(bid, peer, cla)?;
+        Ok(constraints)
+    }
+    /// Marks a bundle pending forwarding as currently not forwardable.
+    ///
+    /// The bundle is kept and can still be forwarded later.
+    pub fn contraindicate(&self, bid: &str) -> Result<Constraints> {
+        self.transition(bid, Transition::Contraindicate)
+    }
+    /// Records the delivery of a bundle for a local endpoint and sends a delivery report if requested.
+    pub fn deliver(&self, bid: &str) -> Result<Constraints> {
+        let constraints = self.transition(bid, Transition::Deliver)?;
+        self.report_status(bid, DELIVERED_BUNDLE, NO_INFORMATION)?;
+        Ok(constraints)
     }
-    pub fn bundle_known(&self, bundle: &Bundle) -> bool {
+    /// Discards a bundle, sends a deletion report if requested and removes its file.
+    ///
+    /// Unlike [`SneakerWorld::remove`] the id is kept with the `DELETED` constraint
+    /// so the bundle is neither received nor synchronised again, garbage collection
+    /// drops it once its lifetime is exceeded.
+    pub fn delete(&self, bid: &str, reason: StatusReportReason) -> Result<()> {
+        Transition::Delete.apply(self.db.get_constraints(bid)?)?;
+        // the bundle is only needed for its report, a missing file must not prevent the deletion
+        let bndl = match self.node_id {
+            Some(_) => self.fs.get_bundle(bid).ok(),
+            None => None,
+        };
+        let path = self.fs.find_file_by_bid(bid)?;
+        let path = path.as_ref().map(|path| path.to_string_lossy());
+        self.db.mark_deleted(bid, path.as_deref())?;
+        self.discard_file(bid, path.as_deref())?;
+        debug!("deleted {}", bid);
+        if let Some(bndl) = bndl {
+            self.report_bundle_status(&bndl, DELETED_BUNDLE, reason)?;
+        }
+        Ok(())
+    }
+    /// Generates a status report for a stored bundle if its flags request one.
+    ///
+    /// The report is added to the store with `FORWARD_PENDING` set, returns
+    /// its id or `None` if no report was requested or no node id is set.
+    pub fn report_status(
+        &self,
+        bid: &str,
+        status: StatusInformationPos,
+        reason: StatusReportReason,
+    ) -> Result<Option<String>> {
+        if self.node_id.is_none() {
+            return Ok(None);
+        }
+        let bndl = self.get_bundle(bid)?;
+        self.report_bundle_status(&bndl, status, reason)
+    }
+    fn report_bundle_status(
+        &self,
+        bndl: &Bundle,
+        status: StatusInformationPos,
+        reason: StatusReportReason,
+    ) -> Result<Option<String>> {
+        let node = match &self.node_id {
+            Some(node) if status::is_requested(bndl, status) => node,
+            _ => return Ok(None),
+        };
+        let mut report = status::new_report_bundle(bndl, node, status, reason);
+        let report_bid = report.id();
+        // deletions happen while making room, their reports must not evict again
+        match self.store(&mut report, status != DELETED_BUNDLE) {
+            Err(D7Error::Full(err)) => {
+                warn!("dropping status report for {}: {}", bndl.id(), err);
+                return Ok(None);
+            }
+            res => res?,
+        };
+        self.db
+            .add_constraints(&report_bid, Constraints::FORWARD_PENDING)?;
+        debug!("created status report {} for {}", report_bid, bndl.id());
+        Ok(Some(report_bid))
+    }
+    /// Records that a bundle was handed to `peer` and sends a forwarding report if requested.
+    pub fn mark_forwarded(&self, bid: &str, peer: &str, cla: Option<&str>) -> Result<()> {
+        self.db.mark_delivered(bid, peer, cla)?;
+        self.report_status(bid, FORWARDED_BUNDLE, NO_INFORMATION)?;
+        Ok(())
+    }
+    /// Updates the referenced bundle with the status report carried by `bndl`.
+    ///
+    /// Reception, forwarding and delivery reports record a delivery to the
+    /// reporting node. A forwarding report also clears `FORWARD_PENDING` and
+    /// `CONTRAINDICATED`, a delivery report `FORWARD_PENDING` and
+    /// `DISPATCH_PENDING`. A deletion report removes the delivery to the
+    /// reporting node so the bundle is forwarded to it again. Reports about
+    /// unknown bundles and reports of this node are ignored, returns `None`
+    /// if `bndl` carries no status report.
+    pub fn process_status_report(&self, bndl: &Bundle) -> Result<Option<StatusReport>> {
+        let report = match status::parse_report(bndl)? {
+            Some(report) => report,
+            None => return Ok(None),
+        };
+        let reporter = &bndl.primary.source;
+        let refbid = report.refbundle();
+        if self.node_id.as_ref() == Some(reporter) || !self.db.exists(&refbid)? {
+            debug!("ignoring status report {} about {}", bndl.id(), refbid);
+            return Ok(Some(report));
+        }
+        let peer = reporter.node_id().unwrap_or_else(|| reporter.to_string());
+        for status in status::asserted(&report) {
+            info!("{} reports status {} for {}", peer, status, refbid);
+            if status == DELETED_BUNDLE {
+                self.db.unmark_delivered(&refbid, &peer)?;
+                continue;
+            }
+            self.db
+                .mark_delivered(&refbid, &peer, Some("status-report"))?;
+            match status {
+                FORWARDED_BUNDLE => self.db.remove_constraints(
+                    &refbid,
+                    Constraints::FORWARD_PENDING | Constraints::CONTRAINDICATED,
+                )?,
+                DELIVERED_BUNDLE => self.db.remove_constraints(
+                    &refbid,
+                    Constraints::FORWARD_PENDING | Constraints::DISPATCH_PENDING,
+                )?,
+                _ => {}
+            }
+        }
+        Ok(Some(report))
+    }
+    /// Processes all stored administrative records, returns the ids of the referenced bundles.
+    pub fn process_status_reports(&self) -> Result<Vec<String>> {
+        let mut processed = Vec::new();
+        for entry in WalkDir::new(self.fs.path_administrative())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            let bndl = match Bundle::try_from(std::fs::read(entry.path())?) {
+                Ok(bndl) => bndl,
+                Err(_) => {
+                    warn!("could not parse bundle file: {:?}", entry.path());
+                    continue;
+                }
+            };
+            if let Some(report) = self.process_status_report(&bndl)? {
+                processed.push(report.refbundle());
+            }
+        }
+        Ok(processed)
+    }
+    pub fn bundle_known(&self, bundle: &Bundle) -> Result<bool> {
         self.db.exists(bundle.id().as_str())
     }
-    pub fn bid_known(&self, bid: &str) -> bool {
+    pub fn bid_known(&self, bid: &str) -> Result<bool> {
         self.db.exists(bid)
     }
     pub fn get_bundle(&self, bid: &str) -> Result<Bundle> {
-        if let Some(path) = self.db.path_for_bundle(bid) {
-            let buf = std::fs::read(path)?;
-            Ok(buf.try_into()?)
+        if !self.db.exists(bid)? {
+            return Err(D7Error::NotFound(bid.to_owned()));
+        }
+        self.fs.get_bundle(bid)
+    }
+    /// Returns the data of the payload block of a stored bundle.
+    pub fn payload(&self, bid: &str) -> Result<Vec<u8>> {
+        self.get_bundle(bid)?
+            .payload()
+            .cloned()
+            .ok_or_else(|| D7Error::NotFound(format!("payload of {}", bid)))
+    }
+    /// Returns a stored bundle or its payload encoded for other tools.
+    pub fn get_encoded(&self, bid: &str, format: BundleFormat) -> Result<Vec<u8>> {
+        let raw = || -> Result<Vec<u8>> {
+            if !self.db.exists(bid)? {
+                return Err(D7Error::NotFound(bid.to_owned()));
+            }
+            let path = self
+                .fs
+                .find_file_by_bid(bid)?
+                .ok_or_else(|| D7Error::NotFound(bid.to_owned()))?;
+            Ok(std::fs::read(path)?)
+        };
+        match format {
+            BundleFormat::Payload => self.payload(bid),
+            BundleFormat::Cbor => raw(),
+            BundleFormat::Hex => Ok(bp7::helpers::hexify(&raw()?).into_bytes()),
+            BundleFormat::Json => Ok(self.get_bundle(bid)?.to_json().into_bytes()),
+        }
+    }
+    /// Saves the bundle file with a logged push intent, the caller inserts the database entry.
+    fn save_logged(&self, bndl: &mut Bundle, pending: (u64, u64)) -> Result<(u64, String)> {
+        let bid = bndl.id();
+        self.make_room(bndl.to_cbor().len() as u64, 1, pending, true)?;
+        let path = self.fs.path_for_bundle_with_filename(bndl);
+        let path = path.to_string_lossy();
+        self.db.log_intent(&bid, Intent::Push, Some(&path))?;
+        self.fs.save_bundle(bndl).inspect_err(|_| {
+            let _ = self.discard_file(&bid, Some(&path));
+        })
+    }
+    /// Copies the file of a bundle to `target`, returns false if it is there already.
+    fn export_file(&self, bid: &str, target: &Path) -> Result<bool> {
+        let src = self
+            .fs
+            .find_file_by_bid(bid)?
+            .ok_or_else(|| D7Error::NotFound(bid.to_owned()))?;
+        let filename = format!("{}.bundle", encode_bid(bid));
+        let dest = target.join(&filename);
+        let size = std::fs::metadata(&src)?.len();
+        if std::fs::metadata(&dest).is_ok_and(|m| m.len() == size) {
+            debug!("{} already exported", bid);
+            return Ok(false);
+        }
+        // media may be unplugged at any time, only complete files get the final name
+        let part = target.join(format!(".{}.part", filename));
+        std::fs::copy(&src, &part)?;
+        std::fs::File::open(&part)?.sync_all()?;
+        std::fs::rename(&part, &dest)?;
+        debug!("exported {} to {}", bid, dest.display());
+        Ok(true)
+    }
+    /// Copies the bundles matching `filter` to the directory `path`, e.g., a mounted USB stick.
+    ///
+    /// Files are named like in the store, so the directory can be read back with
+    /// [`SneakerWorld::import_dir`]. Bundles already present in `path` are skipped.
+    /// With an MTU set, bigger bundles are fragmented and only their fragments are copied.
+    /// Returns the ids of the copied bundles and fragments, followed by the id of each
+    /// bundle whose fragments were copied.
+    pub fn export_dir(&self, path: &str, filter: &BundleFilter) -> Result<Vec<String>> {
+        info!("exporting to {} ({:?})", path, filter);
+        let target = Path::new(path);
+        std::fs::create_dir_all(target)?;
+        let mut exported = Vec::new();
+        // deleted bundles are kept without file
+        let mut seen: HashSet<String> = self
+            .db
+            .filter_constraints(Constraints::DELETED)?
+            .into_iter()
+            .collect();
+        for bid in self.db.filter(filter)? {
+            if seen.contains(&bid) {
+                continue;
+            }
+            let fragments = match self.mtu {
+                Some(mtu) if self.db.get_bundle_entry(&bid)?.size > mtu => {
+                    match self.fragment_bundle(&bid, mtu) {
+                        Err(D7Error::Full(err)) => {
+                            warn!("not exporting {}", err);
+                            continue;
+                        }
+                        res => Some(res?),
+                    }
+                }
+                _ => None,
+            };
+            let mut copied = false;
+            for part in fragments.as_deref().unwrap_or(std::slice::from_ref(&bid)) {
+                if seen.insert(part.clone()) && self.export_file(part, target)? {
+                    exported.push(part.clone());
+                    copied = true;
+                }
+            }
+            if fragments.is_some() && copied {
+                exported.push(bid);
+            }
+        }
+        info!("exported {} bundles", exported.len());
+        Ok(exported)
+    }
+    /// Limits the size of exported bundles, bigger ones are split into fragments.
+    pub fn with_mtu(mut self, mtu: u64) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+    pub fn mtu(&self) -> Option<u64> {
+        self.mtu
+    }
+    /// Splits a stored bundle into fragments of at most `mtu` bytes and stores them as well.
+    ///
+    /// The fragments reference the bundle through their parent id in the database,
+    /// returns their ids ordered by offset. No bundle is evicted for the fragments,
+    /// if they do not fit into the quota none of them is kept.
+    pub fn fragment_bundle(&self, bid: &str, mtu: u64) -> Result<Vec<String>> {
+        let bndl = self.get_bundle(bid)?;
+        let mut bids: Vec<String> = Vec::new();
+        for mut fragment in fragment::fragment(&bndl, mtu)? {
+            // the original bundle must not be evicted while it is exported
+            if let Err(err) = self.store(&mut fragment, false) {
+                for stored in &bids {
+                    self.remove(stored)?;
+                }
+                return Err(err);
+            }
+            bids.push(fragment.id());
+        }
+        debug!("fragmented {} into {} fragments", bid, bids.len());
+        Ok(bids)
+    }
+    /// Copies the bundles missing in `to` together with their constraints.
+    ///
+    /// Deleted bundles are not copied, returns the copied and the skipped bundle ids.
+    fn copy_missing(
+        from: &SneakerWorld,
+        to: &SneakerWorld,
+        bids: Vec<String>,
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        let (mut copied, mut skipped) = (Vec::new(), Vec::new());
+        for bid in bids {
+            let constraints = from.db.get_constraints(&bid)?;
+            if constraints.contains(Constraints::DELETED) {
+                continue;
+            }
+            let mut bndl = from.get_bundle(&bid)?;
+            match to.push(&mut bndl) {
+                Err(D7Error::Full(err)) => {
+                    warn!("not copying {}: {}", bid, err);
+                    skipped.push(bid);
+                    continue;
+                }
+                res => res?,
+            }
+            // bundles for a registered endpoint of `to` keep their local state
+            if !to
+                .db
+                .get_constraints(&bid)?
+                .contains(Constraints::LOCAL_ENDPOINT)
+            {
+                to.db
+                    .set_constraints(&bid, constraints - NODE_LOCAL_CONSTRAINTS)?;
+            }
+            copied.push(bid);
+        }
+        Ok((copied, skipped))
+    }
+    /// Merges this store with `other`, e.g., the local node with a carried USB store.
+    ///
+    /// Only the bundle ids in both databases are compared, the files of
+    /// bundles known to both stores are not touched.
+    pub fn sync_with(&self, other: &SneakerWorld, policy: SyncPolicy) -> Result<SyncReport> {
+        let mine: HashSet<String> = self.db.ids()?.into_iter().collect();
+        let theirs: HashSet<String> = other.db.ids()?.into_iter().collect();
+        let mut report = SyncReport::default();
+        if policy != SyncPolicy::Push {
+            let missing = theirs.difference(&mine).cloned().collect();
+            let (pulled, skipped) = Self::copy_missing(other, self, missing)?;
+            report.pulled = pulled;
+            report.skipped.extend(skipped);
+        }
+        if policy != SyncPolicy::Pull {
+            let missing = mine.difference(&theirs).cloned().collect();
+            let (pushed, skipped) = Self::copy_missing(self, other, missing)?;
+            report.pushed = pushed;
+            report.skipped.extend(skipped);
+        }
+        info!(
+            "sync: pulled {}, pushed {}, skipped {} bundles",
+            report.pulled.len(),
+            report.pushed.len(),
+            report.skipped.len()
+        );
+        Ok(report)
+    }
+    /// Returns true if the lifetime of a bundle without creation time is exceeded.
+    ///
+    /// The age is taken from the bundle age block and advanced by the time the bundle
+    /// has been in the store. Bundles without bundle age block never expire.
+    fn age_exceeded(&self, bid: &str, now: u64) -> Result<bool> {
+        let be = self.db.get_bundle_entry(bid)?;
+        if self.is_deleted(bid)? {
+            // without file the age is unknown, the lifetime is counted from reception
+            return Ok(now.saturating_sub(be.time_added_to_db) >= be.lifetime * 1000);
+        }
+        let bndl = self.fs.get_bundle(bid)?;
+        let age = bndl
+            .extension_block_by_type(BUNDLE_AGE_BLOCK)
+            .and_then(|block| block.bundle_age_get());
+        if let Some(age) = age {
+            let age = age as u64 + now.saturating_sub(be.time_added_to_db);
+            Ok(age >= be.lifetime * 1000)
         } else {
-            anyhow::bail!("unknown bundle");
+            warn!("{} has neither creation time nor bundle age block", bid);
+            Ok(false)
         }
     }
+    /// Removes all bundles whose lifetime is exceeded from the filesystem and the database.
+    ///
+    /// In dry-run mode nothing is changed and the report lists the expired bundles.
+    pub fn gc(&self, dry_run: bool) -> Result<GcReport> {
+        let mut expired = self.db.filter_expired(bp7::dtn_time_now())?;
+        let now = db::unix_time_ms();
+        for bid in self.db.filter_unknown_creation_time()? {
+            if self.age_exceeded(&bid, now)? {
+                expired.push(bid);
+            }
+        }
+
+        let mut report = GcReport {
+            dry_run,
+            ..Default::default()
+        };
+        for bid in expired {
+            report.freed += self.db.get_bundle_entry(&bid)?.size;
+            if !dry_run {
+                debug!("removing expired bundle {}", bid);
+                match self.remove_with_reason(&bid, LIFETIME_EXPIRED) {
+                    Err(D7Error::NotFound(_)) => self.db.delete(&bid)?,
+                    res => res?,
+                }
+            }
+            report.expired.push(bid);
+        }
+        if !dry_run && !report.expired.is_empty() {
+            info!(
+                "removed {} expired bundles ({} bytes)",
+                report.expired.len(),
+                report.freed
+            );
+        }
+        Ok(report)
+    }
     fn import_file(
         &self,
         entry: DirEntry,
+        pending: (u64, u64),
     ) -> Result<Option<(String, BundleEntry, Option<String>)>> {
-        let (filebase, _extension) = entry
+        let filebase = entry
             .file_name()
             .to_str()
-            .unwrap()
-            .rsplit_once('.')
-            .unwrap();
-        let res = if filebase.starts_with("dtn") {
-            let bid = filebase.replace('_', "/").replacen("dtn", "dtn:/", 1);
-            let is_in_db = self.db.exists(&bid);
+            .unwrap_or_default()
+            .trim_end_matches(".bundle");
+        let res = if let Some(bid) = fs::decode_bid(filebase) {
+            let is_in_db = self.db.exists(&bid)?;
             if !is_in_db {
                 let buf = std::fs::read(entry.path())?;
-                let bundle_size = buf.len();
 
                 let mut bndl: Bundle = buf.try_into()?;
-                self.fs.save_bundle(&mut bndl)?;
-                //self.db.insert(&bndl, bundle_size as u64)?;
+                let (bundle_size, path) = self.save_logged(&mut bndl, pending)?;
                 info!("imported {} from {:?}", bndl.id(), entry.path());
                 let mut be = BundleEntry::from(&bndl);
-                be.size = bundle_size as u64;
-                Some((
-                    bndl.id(),
-                    be,
-                    Some(entry.path().to_string_lossy().to_string()),
-                ))
+                be.size = bundle_size;
+                Some((bndl.id(), be, Some(path)))
             } else {
                 debug!("{} already in store", &bid);
                 None
@@ -110,18 +991,13 @@ impl SneakerWorld {
 
             if let Ok(mut bndl) = Bundle::try_from(buf) {
                 let bid = bndl.id();
-                let is_in_db = self.db.exists(&bid);
+                let is_in_db = self.db.exists(&bid)?;
                 if !is_in_db {
-                    let (bundle_size, _path) = self.fs.save_bundle(&mut bndl)?;
-                    //self.db.insert(&bndl, bundle_size)?;
+                    let (bundle_size, path) = self.save_logged(&mut bndl, pending)?;
                     info!("imported {} from {:?}", bndl.id(), entry.path());
                     let mut be = BundleEntry::from(&bndl);
                     be.size = bundle_size;
-                    Some((
-                        bndl.id(),
-                        be,
-                        Some(entry.path().to_string_lossy().to_string()),
-                    ))
+                    Some((bndl.id(), be, Some(path)))
                 } else {
                     debug!("{} already in store", bid);
                     None
@@ -133,10 +1009,15 @@ impl SneakerWorld {
         };
         Ok(res)
     }
+    /// Imports all bundle files in `path`, either all of them are added or none.
+    ///
+    /// Files that cannot be parsed are skipped.
     pub fn import_dir(&self, path: &str, recursive: bool) -> Result<()> {
         info!("importing {} (recursive: {})", path, recursive);
         //let w: crossbeam_deque::Worker<DirEntry> = crossbeam_deque::Worker::new_fifo();
         let mut bes = Vec::new();
+        // imported but not yet inserted bytes and bundles
+        let mut pending = (0, 0);
         let walker = if recursive {
             WalkDir::new(path)
         } else {
@@ -149,11 +1030,33 @@ impl SneakerWorld {
                 .ends_with(".bundle")
         }) {
             //w.push(entry);
-            if let Ok(Some(be)) = self.import_file(entry) {
-                bes.push(be);
+            match self.import_file(entry, pending) {
+                Ok(Some(be)) => {
+                    pending = (pending.0 + be.1.size, pending.1 + 1);
+                    bes.push(be);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    self.abort_import(&bes)?;
+                    return Err(err);
+                }
+            }
+        }
+        if let Err(err) = self.db.insert_bulk(&bes) {
+            self.abort_import(&bes)?;
+            return Err(err);
+        }
+        for (bid, be, path) in &bes {
+            self.track_fragment(bid, be)?;
+            let is_admin = path
+                .as_ref()
+                .is_some_and(|path| Path::new(path).starts_with(self.fs.path_administrative()));
+            if is_admin || self.node_id.is_some() {
+                self.received(&self.fs.get_bundle(bid)?)?;
+            } else {
+                self.dispatch_local(bid, be.parent_bid.is_some())?;
             }
         }
-        self.db.insert_bulk(&bes)?;
 
         // parallel import was not faster..
         /*let mut handles = Vec::new();
@@ -178,4 +1081,790 @@ impl SneakerWorld {
 
         Ok(())
     }
+    fn abort_import(&self, bes: &[(String, BundleEntry, Option<String>)]) -> Result<()> {
+        warn!("import failed, removing {} imported files", bes.len());
+        for (bid, _, path) in bes {
+            self.discard_file(bid, path.as_deref())?;
+        }
+        Ok(())
+    }
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        BundleFilter, BundleFormat, Constraints, D7Error, Intent, LargestFirst, Quota, SendOptions,
+        SneakerWorld, SyncPolicy,
+    };
+    use bp7::canonical::new_bundle_age_block;
+    use bp7::flags::BlockControlFlags;
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn in_memory_world_test() {
+        let world = SneakerWorld::open_in_memory().unwrap();
+        assert!(world.db.is_in_memory() && world.fs.is_temporary());
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        world.push(&mut bndl).unwrap();
+        assert!(world.bundle_known(&bndl).unwrap());
+        assert_eq!(world.get_bundle(&bndl.id()).unwrap().id(), bndl.id());
+
+        let base = PathBuf::from(world.fs.path_single().parent().unwrap());
+        let other = world.clone();
+        drop(world);
+        assert!(base.exists());
+        other.remove(&bndl.id()).unwrap();
+        assert!(!other.bid_known(&bndl.id()).unwrap());
+        drop(other);
+        assert!(!base.exists());
+    }
+
+    #[test]
+    fn crash_repair_test() {
+        let tmp = crate::fs::TempDir::new().unwrap();
+        let base = tmp.path().to_str().unwrap();
+        let world = SneakerWorld::open(base).unwrap();
+        let mut removed = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        world.push(&mut removed).unwrap();
+        let mut deleted = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        world.push(&mut deleted).unwrap();
+
+        // crash after the file was written but before the row was inserted
+        let mut pushed = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        let path = world.fs.path_for_bundle_with_filename(&pushed);
+        let path = path.to_string_lossy();
+        world
+            .db
+            .log_intent(&pushed.id(), Intent::Push, Some(&path))
+            .unwrap();
+        world.fs.save_bundle(&mut pushed).unwrap();
+
+        // crash after the row was deleted but before the file was removed
+        let removed_path = world.fs.find_file_by_bid(&removed.id()).unwrap().unwrap();
+        world
+            .db
+            .delete_logged(&removed.id(), Some(&removed_path.to_string_lossy()))
+            .unwrap();
+
+        // crash after the bundle was marked deleted but before the file was removed
+        let deleted_path = world.fs.find_file_by_bid(&deleted.id()).unwrap().unwrap();
+        world
+            .db
+            .mark_deleted(&deleted.id(), Some(&deleted_path.to_string_lossy()))
+            .unwrap();
+
+        // crash before the file was written
+        world
+            .db
+            .log_intent(
+                "dtn://lost/-1-1",
+                Intent::Push,
+                Some(&format!("{}/lost.bundle", base)),
+            )
+            .unwrap();
+        drop(world);
+
+        let world = SneakerWorld::open(base).unwrap();
+        assert!(world.db.intents().unwrap().is_empty());
+        assert_eq!(world.get_bundle(&pushed.id()).unwrap().id(), pushed.id());
+        assert!(!world.bid_known(&removed.id()).unwrap());
+        assert!(!removed_path.exists());
+        assert!(world.is_deleted(&deleted.id()).unwrap());
+        assert!(!deleted_path.exists());
+        assert_eq!(world.db.len().unwrap(), 2);
+        assert_eq!(world.repair().unwrap(), 0);
+    }
+
+    #[test]
+    fn gc_test() {
+        let world = SneakerWorld::open_in_memory().unwrap();
+        let now = bp7::dtn_time_now();
+        let mut fresh = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        let mut expired =
+            bp7::helpers::rnd_bundle(bp7::CreationTimestamp::with_time_and_seq(now - 10_000, 1));
+        expired.primary.lifetime = Duration::from_secs(5);
+
+        let mut aged = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::with_time_and_seq(0, 1));
+        aged.primary.lifetime = Duration::from_secs(60);
+        let mut young = aged.clone();
+        young.primary.creation_timestamp = bp7::CreationTimestamp::with_time_and_seq(0, 2);
+        aged.add_canonical_block(new_bundle_age_block(0, BlockControlFlags::empty(), 61_000));
+        young.add_canonical_block(new_bundle_age_block(0, BlockControlFlags::empty(), 1_000));
+
+        for bndl in [&mut fresh, &mut expired, &mut aged, &mut young] {
+            world.push(bndl).unwrap();
+        }
+
+        let report = world.gc(true).unwrap();
+        let mut bids = vec![expired.id(), aged.id()];
+        bids.sort();
+        let mut reported = report.expired.clone();
+        reported.sort();
+        assert_eq!(reported, bids);
+        assert!(report.freed > 0);
+        assert_eq!(world.db.len().unwrap(), 4);
+
+        assert_eq!(world.gc(false).unwrap().expired.len(), 2);
+        assert!(!world.bid_known(&expired.id()).unwrap());
+        assert!(!world.bid_known(&aged.id()).unwrap());
+        assert!(world.bid_known(&fresh.id()).unwrap());
+        assert!(world.bid_known(&young.id()).unwrap());
+        assert!(world.gc(false).unwrap().expired.is_empty());
+
+        // deleted bundles without creation time are kept for their lifetime as well
+        world
+            .delete(&young.id(), bp7::administrative_record::NO_INFORMATION)
+            .unwrap();
+        assert!(world.gc(false).unwrap().expired.is_empty());
+        assert!(world.is_deleted(&young.id()).unwrap());
+    }
+
+    #[test]
+    fn quota_test() {
+        let world = SneakerWorld::open_in_memory()
+            .unwrap()
+            .with_quota(Quota::unlimited().max_bundles(2));
+        let mut bundles: Vec<_> = (0..4)
+            .map(|_| bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now()))
+            .collect();
+        world.push(&mut bundles[0]).unwrap();
+        world
+            .db
+            .add_constraints(&bundles[0].id(), Constraints::LOCAL_ENDPOINT)
+            .unwrap();
+        world.push(&mut bundles[1]).unwrap();
+        world.push(&mut bundles[2]).unwrap();
+        assert!(world.bid_known(&bundles[0].id()).unwrap());
+        assert!(!world.bid_known(&bundles[1].id()).unwrap());
+        assert_eq!(world.db.len().unwrap(), 2);
+
+        world
+            .db
+            .add_constraints(&bundles[2].id(), Constraints::LOCAL_ENDPOINT)
+            .unwrap();
+        assert!(matches!(world.push(&mut bundles[3]), Err(D7Error::Full(_))));
+        assert!(!world.bid_known(&bundles[3].id()).unwrap());
+        assert_eq!(world.db.len().unwrap(), 2);
+
+        let size = world.db.total_size().unwrap();
+        let world = world.with_quota(Quota::unlimited().max_bytes(size).policy(LargestFirst));
+        world.push(&mut bundles[3]).unwrap();
+        assert!(world.db.total_size().unwrap() <= size);
+
+        // deletion reports of evicted bundles count towards the quota as well
+        let world = SneakerWorld::open_in_memory()
+            .unwrap()
+            .with_node_id(bp7::EndpointID::try_from("dtn://me/").unwrap())
+            .with_quota(Quota::unlimited().max_bundles(2));
+        for _ in 0..4 {
+            let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+            bndl.primary.bundle_control_flags |=
+                bp7::flags::BundleControlFlags::BUNDLE_STATUS_REQUEST_DELETION.bits();
+            world.push(&mut bndl).unwrap();
+            assert!(world.bid_known(&bndl.id()).unwrap());
+            assert!(world.db.len().unwrap() <= 2);
+        }
+
+        // fragments for an export never evict the original bundle
+        let world = SneakerWorld::open_in_memory().unwrap();
+        let mut big = crate::fragment::tests::big_bundle(1000);
+        world.push(&mut big).unwrap();
+        let size = world.db.total_size().unwrap();
+        let world = world.with_quota(Quota::unlimited().max_bytes(size + 500));
+        assert!(matches!(
+            world.fragment_bundle(&big.id(), 400),
+            Err(D7Error::Full(_))
+        ));
+        assert!(world.bid_known(&big.id()).unwrap());
+        assert_eq!(world.db.len().unwrap(), 1);
+
+        // deleted bundles neither count nor get evicted
+        let world = SneakerWorld::open_in_memory()
+            .unwrap()
+            .with_quota(Quota::unlimited().max_bundles(1));
+        let mut bundles: Vec<_> = (0..3)
+            .map(|_| bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now()))
+            .collect();
+        world.push(&mut bundles[0]).unwrap();
+        world
+            .delete(&bundles[0].id(), bp7::administrative_record::NO_INFORMATION)
+            .unwrap();
+        world.push(&mut bundles[1]).unwrap();
+        world.push(&mut bundles[2]).unwrap();
+        assert!(world.is_deleted(&bundles[0].id()).unwrap());
+        assert!(!world.bid_known(&bundles[1].id()).unwrap());
+        assert_eq!(world.db.stored_len().unwrap(), 1);
+    }
+
+    #[test]
+    fn export_test() {
+        let tmp = crate::fs::TempDir::new().unwrap();
+        let target = tmp.path().to_str().unwrap();
+        let world = SneakerWorld::open_in_memory().unwrap();
+        let mut bundles: Vec<_> = (0..3)
+            .map(|_| bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now()))
+            .collect();
+        for bndl in bundles.iter_mut() {
+            world.push(bndl).unwrap();
+        }
+        let forward: Constraints = "forward_pending, local_endpoint".parse().unwrap();
+        assert_eq!(
+            forward,
+            Constraints::FORWARD_PENDING | Constraints::LOCAL_ENDPOINT
+        );
+        world.db.set_constraints(&bundles[1].id(), forward).unwrap();
+
+        let filter = BundleFilter::Constraints(Constraints::FORWARD_PENDING);
+        assert_eq!(
+            world.export_dir(target, &filter).unwrap(),
+            vec![bundles[1].id()]
+        );
+        assert!(world.export_dir(target, &filter).unwrap().is_empty());
+        assert_eq!(
+            world.export_dir(target, &BundleFilter::All).unwrap().len(),
+            2
+        );
+
+        let other = SneakerWorld::open_in_memory().unwrap();
+        other.import_dir(target, false).unwrap();
+        let mut ids = other.db.ids().unwrap();
+        ids.sort();
+        let mut expected = world.db.ids().unwrap();
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn sync_test() {
+        let local = SneakerWorld::open_in_memory().unwrap();
+        let carried = SneakerWorld::open_in_memory().unwrap();
+        let mut bundles: Vec<_> = (0..4)
+            .map(|_| bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now()))
+            .collect();
+        local.push(&mut bundles[0]).unwrap();
+        local.push(&mut bundles[1]).unwrap();
+        carried.push(&mut bundles[1]).unwrap();
+        carried.push(&mut bundles[2]).unwrap();
+        carried.push(&mut bundles[3]).unwrap();
+        local
+            .db
+            .set_constraints(
+                &bundles[0].id(),
+                Constraints::FORWARD_PENDING | Constraints::LOCAL_ENDPOINT,
+            )
+            .unwrap();
+        carried
+            .db
+            .set_constraints(&bundles[3].id(), Constraints::DELETED)
+            .unwrap();
+
+        let report = local.sync_with(&carried, SyncPolicy::Pull).unwrap();
+        assert_eq!(report.pulled, vec![bundles[2].id()]);
+        assert!(report.pushed.is_empty());
+        assert!(!local.bid_known(&bundles[3].id()).unwrap());
+
+        let report = local.sync_with(&carried, SyncPolicy::Both).unwrap();
+        assert!(report.pulled.is_empty());
+        assert_eq!(report.pushed, vec![bundles[0].id()]);
+        assert_eq!(
+            carried.db.get_constraints(&bundles[0].id()).unwrap(),
+            Constraints::FORWARD_PENDING
+        );
+        assert_eq!(
+            carried.get_bundle(&bundles[0].id()).unwrap().id(),
+            bundles[0].id()
+        );
+    }
+
+    #[test]
+    fn reassembly_test() {
+        let world = SneakerWorld::open_in_memory().unwrap();
+        let mut bndl = crate::fragment::tests::big_bundle(100);
+        let mut fragments = crate::fragment::tests::split(&bndl, 40);
+        world.push(&mut fragments[2]).unwrap();
+        world.push(&mut fragments[0]).unwrap();
+        assert_eq!(
+            world.db.get_constraints(&fragments[0].id()).unwrap(),
+            Constraints::REASSEMBLY_PENDING
+        );
+        assert_eq!(world.db.pending_reassemblies().unwrap(), vec![bndl.id()]);
+        assert!(world.reassemble(&bndl.id()).unwrap().is_none());
+        assert!(world.reassemble_all().unwrap().is_empty());
+
+        world.push(&mut fragments[1]).unwrap();
+        assert_eq!(world.reassemble_all().unwrap(), vec![bndl.id()]);
+        let mut stored = world.get_bundle(&bndl.id()).unwrap();
+        assert_eq!(stored.to_cbor(), bndl.to_cbor());
+        assert!(world.db.pending_reassemblies().unwrap().is_empty());
+        assert_eq!(
+            world.db.get_constraints(&fragments[1].id()).unwrap(),
+            Constraints::empty()
+        );
+        assert_eq!(world.db.len().unwrap(), 4);
+
+        // fragments of stored bundles need no reassembly
+        let other = SneakerWorld::open_in_memory().unwrap();
+        other.push(&mut bndl).unwrap();
+        other.push(&mut fragments[0]).unwrap();
+        assert!(other.db.pending_reassemblies().unwrap().is_empty());
+    }
+
+    #[test]
+    fn export_fragments_test() {
+        let tmp = crate::fs::TempDir::new().unwrap();
+        let target = tmp.path().to_str().unwrap();
+        let world = SneakerWorld::open_in_memory()
+            .unwrap()
+            .with_mtu(400)
+            .with_node_id(bp7::EndpointID::try_from("dtn://exporter/").unwrap());
+        let mut big = crate::fragment::tests::big_bundle(1000);
+        big.primary.bundle_control_flags |=
+            bp7::flags::BundleControlFlags::BUNDLE_STATUS_REQUEST_RECEPTION.bits();
+        let mut small = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        world.push(&mut big).unwrap();
+        world.push(&mut small).unwrap();
+        let reports = world
+            .db
+            .filter_constraints(Constraints::FORWARD_PENDING)
+            .unwrap();
+        assert_eq!(reports.len(), 1);
+
+        let exported = world.export_dir(target, &BundleFilter::All).unwrap();
+        assert!(exported.contains(&small.id()));
+        assert!(exported.contains(&big.id()));
+        let fragments = world.db.fragments_of(&big.id()).unwrap();
+        assert!(fragments.len() > 2);
+        // the reception report of big is exported as well
+        assert_eq!(exported.len(), fragments.len() + 3);
+        assert!(world.db.pending_reassemblies().unwrap().is_empty());
+        // fragmenting is no reception
+        assert_eq!(
+            world
+                .db
+                .filter_constraints(Constraints::FORWARD_PENDING)
+                .unwrap(),
+            reports
+        );
+        assert!(world
+            .export_dir(target, &BundleFilter::All)
+            .unwrap()
+            .is_empty());
+        for entry in std::fs::read_dir(target).unwrap() {
+            assert!(entry.unwrap().metadata().unwrap().len() <= 400);
+        }
+
+        let other = SneakerWorld::open_in_memory().unwrap();
+        other.import_dir(target, false).unwrap();
+        assert!(!other.bid_known(&big.id()).unwrap());
+        assert_eq!(other.reassemble_all().unwrap(), vec![big.id()]);
+        assert_eq!(
+            other.get_bundle(&big.id()).unwrap().to_cbor(),
+            big.to_cbor()
+        );
+    }
+
+    #[test]
+    fn indexed_lookup_test() {
+        let world = SneakerWorld::open_in_memory().unwrap();
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        world.push(&mut bndl).unwrap();
+        let path = world.fs.find_file_by_bid(&bndl.id()).unwrap().unwrap();
+        assert_eq!(
+            world.db.path_for_bundle(&bndl.id()).unwrap(),
+            Some(path.to_string_lossy().to_string())
+        );
+
+        // outdated paths are corrected
+        world
+            .db
+            .set_path(&bndl.id(), Some("/nonexistent.bundle".into()))
+            .unwrap();
+        assert_eq!(
+            world.fs.find_file_by_bid(&bndl.id()).unwrap(),
+            Some(path.clone())
+        );
+        assert_eq!(
+            world.db.path_for_bundle(&bndl.id()).unwrap(),
+            Some(path.to_string_lossy().to_string())
+        );
+
+        assert_eq!(
+            world.fs.find_file_by_bid("dtn://unknown/-0-0").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn status_report_test() {
+        use bp7::administrative_record::{DELIVERED_BUNDLE, NO_INFORMATION};
+        use bp7::flags::BundleControlFlags;
+
+        let origin = SneakerWorld::open_in_memory().unwrap();
+        let node = bp7::EndpointID::try_from("dtn://relay/").unwrap();
+        let relay = SneakerWorld::open_in_memory()
+            .unwrap()
+            .with_node_id(node.clone());
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        bndl.primary.bundle_control_flags |= (BundleControlFlags::BUNDLE_STATUS_REQUEST_RECEPTION
+            | BundleControlFlags::BUNDLE_STATUS_REQUEST_DELETION)
+            .bits();
+        let bid = bndl.id();
+        origin.push(&mut bndl).unwrap();
+        origin
+            .db
+            .add_constraints(&bid, Constraints::FORWARD_PENDING)
+            .unwrap();
+
+        // reception report
+        relay.push(&mut bndl).unwrap();
+        let reports = relay
+            .db
+            .filter_constraints(Constraints::FORWARD_PENDING)
+            .unwrap();
+        assert_eq!(reports.len(), 1);
+        let mut report = relay.get_bundle(&reports[0]).unwrap();
+        assert!(report.is_administrative_record());
+        assert_eq!(report.primary.destination, bndl.primary.report_to);
+        origin.push(&mut report).unwrap();
+        let deliveries = origin.db.delivered_to(&bid).unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].peer, node.node_id().unwrap());
+        assert!(origin
+            .db
+            .get_constraints(&bid)
+            .unwrap()
+            .contains(Constraints::FORWARD_PENDING));
+
+        // delivery report
+        let report_bid = relay
+            .report_status(&bid, DELIVERED_BUNDLE, NO_INFORMATION)
+            .unwrap()
+            .unwrap();
+        origin
+            .push(&mut relay.get_bundle(&report_bid).unwrap())
+            .unwrap();
+        assert!(!origin
+            .db
+            .get_constraints(&bid)
+            .unwrap()
+            .contains(Constraints::FORWARD_PENDING));
+        assert_eq!(
+            origin.process_status_reports().unwrap(),
+            vec![bid.clone(); 2]
+        );
+
+        // deletion report, forwarding was not requested
+        relay.mark_forwarded(&bid, "peer", None).unwrap();
+        relay.remove(&bid).unwrap();
+        assert_eq!(
+            relay
+                .db
+                .filter_constraints(Constraints::FORWARD_PENDING)
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // no reports without node id
+        origin.remove(&bid).unwrap();
+        assert_eq!(origin.db.len().unwrap(), 2);
+    }
+
+    #[test]
+    fn forwarded_report_test() {
+        use bp7::administrative_record::{FORWARDED_BUNDLE, NO_INFORMATION};
+
+        let origin = SneakerWorld::open_in_memory().unwrap();
+        let relay = bp7::EndpointID::try_from("dtn://relay/").unwrap();
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        let bid = bndl.id();
+        origin.push(&mut bndl).unwrap();
+        origin
+            .db
+            .add_constraints(&bid, Constraints::CONTRAINDICATED)
+            .unwrap();
+
+        let mut report =
+            crate::status::new_report_bundle(&bndl, &relay, FORWARDED_BUNDLE, NO_INFORMATION);
+        origin.push(&mut report).unwrap();
+        let deliveries = origin.db.delivered_to(&bid).unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].peer, relay.node_id().unwrap());
+        assert!(!origin
+            .db
+            .get_constraints(&bid)
+            .unwrap()
+            .intersects(Constraints::FORWARD_PENDING | Constraints::CONTRAINDICATED));
+    }
+
+    #[test]
+    fn deleted_report_test() {
+        use bp7::administrative_record::{DELETED_BUNDLE, NO_INFORMATION, RECEIVED_BUNDLE};
+
+        let origin = SneakerWorld::open_in_memory().unwrap();
+        let relay = bp7::EndpointID::try_from("dtn://relay/").unwrap();
+        let peer = relay.node_id().unwrap();
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        let bid = bndl.id();
+        origin.push(&mut bndl).unwrap();
+
+        // a deletion report alone is not a delivery
+        let mut report =
+            crate::status::new_report_bundle(&bndl, &relay, DELETED_BUNDLE, NO_INFORMATION);
+        origin.push(&mut report).unwrap();
+        assert!(origin.db.delivered_to(&bid).unwrap().is_empty());
+
+        let mut report =
+            crate::status::new_report_bundle(&bndl, &relay, RECEIVED_BUNDLE, NO_INFORMATION);
+        origin.push(&mut report).unwrap();
+        assert!(!origin.db.pending_for_peer(&peer).unwrap().contains(&bid));
+
+        // the relay dropped the bundle, so it has to be forwarded again
+        let mut report =
+            crate::status::new_report_bundle(&bndl, &relay, DELETED_BUNDLE, NO_INFORMATION);
+        origin.push(&mut report).unwrap();
+        assert!(origin.db.delivered_to(&bid).unwrap().is_empty());
+        assert!(origin.db.pending_for_peer(&peer).unwrap().contains(&bid));
+    }
+
+    #[test]
+    fn lifecycle_test() {
+        use bp7::administrative_record::NO_INFORMATION;
+
+        let world = SneakerWorld::open_in_memory()
+            .unwrap()
+            .with_node_id(bp7::EndpointID::try_from("dtn://local/").unwrap());
+        let src = bp7::EndpointID::try_from("dtn://other/app").unwrap();
+        let mut local = bp7::bundle::new_std_payload_bundle(
+            src.clone(),
+            bp7::EndpointID::try_from("dtn://local/inbox").unwrap(),
+            b"hello".to_vec(),
+        );
+        let mut remote = bp7::bundle::new_std_payload_bundle(
+            src,
+            bp7::EndpointID::try_from("dtn://far/inbox").unwrap(),
+            b"world".to_vec(),
+        );
+        world.push(&mut local).unwrap();
+        world.push(&mut remote).unwrap();
+
+        let bid = local.id();
+        assert_eq!(world.receive(&bid).unwrap(), Constraints::DISPATCH_PENDING);
+        assert!(matches!(
+            world.deliver(&bid),
+            Err(D7Error::IllegalTransition(_))
+        ));
+        assert_eq!(world.dispatch(&bid).unwrap(), Constraints::LOCAL_ENDPOINT);
+        assert_eq!(world.deliver(&bid).unwrap(), Constraints::empty());
+        assert!(world.deliver(&bid).is_err());
+
+        let bid = remote.id();
+        world.receive(&bid).unwrap();
+        assert_eq!(world.dispatch(&bid).unwrap(), Constraints::FORWARD_PENDING);
+        assert_eq!(
+            world.contraindicate(&bid).unwrap(),
+            Constraints::CONTRAINDICATED
+        );
+        assert_eq!(
+            world.forward(&bid, "peer", None).unwrap(),
+            Constraints::empty()
+        );
+        assert_eq!(world.db.delivered_to(&bid).unwrap()[0].peer, "peer");
+
+        // deleted bundles keep their id but lose their file
+        world.delete(&bid, NO_INFORMATION).unwrap();
+        assert!(world.bid_known(&bid).unwrap());
+        assert_eq!(
+            world.db.get_constraints(&bid).unwrap(),
+            Constraints::DELETED
+        );
+        assert_eq!(world.fs.find_file_by_bid(&bid).unwrap(), None);
+        // bundles whose file is missing can be deleted as well
+        let mut lost = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        world.push(&mut lost).unwrap();
+        let path = world.fs.find_file_by_bid(&lost.id()).unwrap().unwrap();
+        std::fs::remove_file(path).unwrap();
+        world.delete(&lost.id(), NO_INFORMATION).unwrap();
+        assert!(world.is_deleted(&lost.id()).unwrap());
+        // the filesystem is not searched for a deleted bundle
+        let (_, stray) = world.fs.save_bundle(&mut remote.clone()).unwrap();
+        assert_eq!(world.fs.find_file_by_bid(&bid).unwrap(), None);
+        std::fs::remove_file(stray).unwrap();
+        assert!(world.receive(&bid).is_err());
+        assert!(world.delete(&bid, NO_INFORMATION).is_err());
+        world.push(&mut remote).unwrap();
+        assert_eq!(world.fs.find_file_by_bid(&bid).unwrap(), None);
+        assert_eq!(
+            world.db.get_constraints(&bid).unwrap(),
+            Constraints::DELETED
+        );
+        world.db.sync_with_fs(&world.fs).unwrap();
+        assert!(world.bid_known(&bid).unwrap());
+        assert!(world.gc(false).unwrap().expired.is_empty());
+        world.remove(&bid).unwrap();
+        assert!(!world.bid_known(&bid).unwrap());
+    }
+
+    #[test]
+    fn local_delivery_test() {
+        let world = SneakerWorld::open_in_memory().unwrap();
+        let carried = SneakerWorld::open_in_memory().unwrap();
+        let chat = bp7::EndpointID::try_from("dtn://me/chat").unwrap();
+        let src = bp7::EndpointID::try_from("dtn://other/chat").unwrap();
+        let mut bundles: Vec<_> = (0..3)
+            .map(|i| bp7::bundle::new_std_payload_bundle(src.clone(), chat.clone(), vec![i; 4]))
+            .collect();
+        let mut other = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+
+        // stored before the registration
+        world.push(&mut bundles[0]).unwrap();
+        world.receive(&bundles[0].id()).unwrap();
+        assert_eq!(
+            world.register_endpoint(&chat).unwrap(),
+            vec![bundles[0].id()]
+        );
+        world.push(&mut bundles[1]).unwrap();
+        world.push(&mut other).unwrap();
+        carried.push(&mut bundles[2]).unwrap();
+        carried
+            .db
+            .set_constraints(&bundles[2].id(), Constraints::FORWARD_PENDING)
+            .unwrap();
+        world.sync_with(&carried, SyncPolicy::Pull).unwrap();
+
+        let chat = chat.to_string();
+        let pending = world.pending_deliveries(&chat).unwrap();
+        assert_eq!(pending.len(), 3);
+        assert!(!pending.contains(&other.id()));
+        assert_eq!(
+            world.db.get_constraints(&other.id()).unwrap(),
+            Constraints::empty()
+        );
+
+        let consumed = world.consume(&chat).unwrap();
+        assert_eq!(consumed.iter().map(|b| b.id()).collect::<Vec<_>>(), pending);
+        assert!(world.pending_deliveries(&chat).unwrap().is_empty());
+        assert!(world.consume(&chat).unwrap().is_empty());
+        assert_eq!(world.db.delivered_to(&pending[0]).unwrap()[0].peer, chat);
+
+        assert!(world.unregister_endpoint(&chat).unwrap().is_empty());
+        assert!(world.db.endpoints().unwrap().is_empty());
+
+        // undelivered bundles are forwarded once their endpoint is gone
+        let chat_eid = bp7::EndpointID::try_from(chat.as_str()).unwrap();
+        world.register_endpoint(&chat_eid).unwrap();
+        let mut late = bp7::bundle::new_std_payload_bundle(src.clone(), chat_eid, vec![3; 4]);
+        world.push(&mut late).unwrap();
+        assert_eq!(world.pending_deliveries(&chat).unwrap(), vec![late.id()]);
+        assert_eq!(world.unregister_endpoint(&chat).unwrap(), vec![late.id()]);
+        assert_eq!(
+            world.db.get_constraints(&late.id()).unwrap(),
+            Constraints::FORWARD_PENDING
+        );
+
+        // the node id only matches destinations of the same scheme
+        let world = SneakerWorld::open_in_memory()
+            .unwrap()
+            .with_node_id(bp7::EndpointID::try_from("ipn:5.0").unwrap());
+        for (dst, expected) in [
+            ("dtn://5/inbox", Constraints::FORWARD_PENDING),
+            ("ipn:5.1", Constraints::LOCAL_ENDPOINT),
+            ("ipn:6.1", Constraints::FORWARD_PENDING),
+        ] {
+            let mut bndl = bp7::bundle::new_std_payload_bundle(
+                src.clone(),
+                bp7::EndpointID::try_from(dst).unwrap(),
+                Vec::new(),
+            );
+            world.push(&mut bndl).unwrap();
+            world.receive(&bndl.id()).unwrap();
+            assert_eq!(world.dispatch(&bndl.id()).unwrap(), expected, "{}", dst);
+        }
+    }
+
+    #[test]
+    fn send_test() {
+        use bp7::flags::BundleControlFlags;
+
+        let tmp = crate::fs::TempDir::new().unwrap();
+        let base = tmp.path().to_str().unwrap();
+        let app = bp7::EndpointID::try_from("dtn://me/app").unwrap();
+        let chat = bp7::EndpointID::try_from("dtn://me/chat").unwrap();
+        let far = bp7::EndpointID::try_from("dtn://far/inbox").unwrap();
+        let options = SendOptions::default()
+            .lifetime(Duration::from_secs(60))
+            .flags(BundleControlFlags::BUNDLE_STATUS_REQUEST_DELIVERY);
+        {
+            let world = SneakerWorld::open(base).unwrap();
+            world.register_endpoint(&chat).unwrap();
+            let local = world.send(&app, &chat, b"hi".to_vec(), &options).unwrap();
+            let remote = world.send(&app, &far, b"ho".to_vec(), &options).unwrap();
+            assert_eq!(
+                world.pending_deliveries(&chat.to_string()).unwrap(),
+                vec![local.clone()]
+            );
+            assert_eq!(
+                world.db.get_constraints(&remote).unwrap(),
+                Constraints::FORWARD_PENDING
+            );
+
+            let bndl = world.get_bundle(&local).unwrap();
+            assert_eq!(bndl.primary.creation_timestamp.seqno(), 0);
+            assert_eq!(bndl.primary.lifetime, Duration::from_secs(60));
+            assert_eq!(bndl.primary.report_to, app);
+            assert_eq!(bndl.payload().unwrap(), b"hi");
+            assert_eq!(bndl.primary.crc.to_code(), bp7::crc::CRC_32);
+
+            let invalid = options
+                .clone()
+                .flags(BundleControlFlags::BUNDLE_IS_FRAGMENT);
+            assert!(matches!(
+                world.send(&app, &far, Vec::new(), &invalid),
+                Err(D7Error::Parse(_))
+            ));
+        }
+        // counters survive a restart, the rejected bundle did not use up a seqno
+        let world = SneakerWorld::open(base).unwrap();
+        let bid = world.send(&app, &far, Vec::new(), &options).unwrap();
+        assert!(bid.ends_with("-2"), "{}", bid);
+        let bid = world.send(&chat, &far, Vec::new(), &options).unwrap();
+        assert!(bid.ends_with("-0"), "{}", bid);
+    }
+
+    #[test]
+    fn payload_test() {
+        let world = SneakerWorld::open_in_memory().unwrap();
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        world.push(&mut bndl).unwrap();
+        let bid = bndl.id();
+        assert_eq!(world.payload(&bid).unwrap(), b"ABC");
+        assert_eq!(
+            world.get_encoded(&bid, BundleFormat::Payload).unwrap(),
+            b"ABC"
+        );
+        let cbor = world.get_encoded(&bid, BundleFormat::Cbor).unwrap();
+        assert_eq!(cbor, bndl.to_cbor());
+        assert_eq!(
+            world.get_encoded(&bid, BundleFormat::Hex).unwrap(),
+            bp7::helpers::hexify(&cbor).into_bytes()
+        );
+        let json = world.get_encoded(&bid, BundleFormat::Json).unwrap();
+        assert!(String::from_utf8(json).unwrap().starts_with('['));
+
+        assert_eq!("JSON".parse::<BundleFormat>().unwrap(), BundleFormat::Json);
+        assert!("xml".parse::<BundleFormat>().is_err());
+        assert!(matches!(
+            world.get_encoded("dtn://unknown/-0-0", BundleFormat::Cbor),
+            Err(D7Error::NotFound(_))
+        ));
+    }
 }
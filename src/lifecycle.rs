@@ -0,0 +1,132 @@
+//! Bundle processing steps of RFC 9171 section 5 as transitions between constraints.
+//!
+//! [`Transition::apply`] only checks and computes the new constraints, the
+//! side effects like status reports and file removal are done by the
+//! lifecycle methods of [`crate::SneakerWorld`].
+use std::fmt;
+
+use crate::error::{D7Error, Result};
+use crate::Constraints;
+
+/// Step in the processing of a stored bundle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// the bundle was received and awaits dispatching
+    Receive,
+    /// the bundle is either delivered locally or forwarded
+    Dispatch { local: bool },
+    /// the bundle was handed to a peer or convergence layer
+    Forward,
+    /// forwarding is currently not possible
+    Contraindicate,
+    /// the bundle was delivered to a local application
+    Deliver,
+    /// the bundle is discarded, only its id is retained
+    Delete,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Transition::Receive => "receive",
+            Transition::Dispatch { .. } => "dispatch",
+            Transition::Forward => "forward",
+            Transition::Contraindicate => "contraindicate",
+            Transition::Deliver => "deliver",
+            Transition::Delete => "delete",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Transition {
+    /// Returns the constraints after the transition or an error if it is not allowed.
+    pub fn apply(self, constraints: Constraints) -> Result<Constraints> {
+        let illegal = || {
+            Err(D7Error::IllegalTransition(format!(
+                "cannot {} bundle with constraints {:?}",
+                self, constraints
+            )))
+        };
+        if constraints.contains(Constraints::DELETED) {
+            return illegal();
+        }
+        let pending = Constraints::DISPATCH_PENDING
+            | Constraints::FORWARD_PENDING
+            | Constraints::CONTRAINDICATED
+            | Constraints::LOCAL_ENDPOINT;
+        match self {
+            Transition::Receive if !constraints.intersects(pending) => {
+                Ok(constraints | Constraints::DISPATCH_PENDING)
+            }
+            Transition::Dispatch { local }
+                if constraints.contains(Constraints::DISPATCH_PENDING) =>
+            {
+                let target = if local {
+                    Constraints::LOCAL_ENDPOINT
+                } else {
+                    Constraints::FORWARD_PENDING
+                };
+                Ok((constraints - Constraints::DISPATCH_PENDING) | target)
+            }
+            Transition::Forward
+                if constraints
+                    .intersects(Constraints::FORWARD_PENDING | Constraints::CONTRAINDICATED) =>
+            {
+                Ok(constraints - Constraints::FORWARD_PENDING - Constraints::CONTRAINDICATED)
+            }
+            Transition::Contraindicate if constraints.contains(Constraints::FORWARD_PENDING) => {
+                Ok((constraints - Constraints::FORWARD_PENDING) | Constraints::CONTRAINDICATED)
+            }
+            Transition::Deliver
+                if constraints.contains(Constraints::LOCAL_ENDPOINT)
+                    && !constraints.contains(Constraints::REASSEMBLY_PENDING) =>
+            {
+                Ok(constraints - Constraints::LOCAL_ENDPOINT)
+            }
+            Transition::Delete => Ok(Constraints::DELETED),
+            _ => illegal(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transition_test() {
+        let received = Transition::Receive.apply(Constraints::empty()).unwrap();
+        assert_eq!(received, Constraints::DISPATCH_PENDING);
+        assert!(Transition::Receive.apply(received).is_err());
+        assert!(Transition::Forward.apply(received).is_err());
+
+        let forwarding = Transition::Dispatch { local: false }
+            .apply(received)
+            .unwrap();
+        assert_eq!(forwarding, Constraints::FORWARD_PENDING);
+        let contraindicated = Transition::Contraindicate.apply(forwarding).unwrap();
+        assert_eq!(contraindicated, Constraints::CONTRAINDICATED);
+        assert_eq!(
+            Transition::Forward.apply(contraindicated).unwrap(),
+            Constraints::empty()
+        );
+
+        let local = Transition::Dispatch { local: true }
+            .apply(received)
+            .unwrap();
+        assert_eq!(local, Constraints::LOCAL_ENDPOINT);
+        assert!(matches!(
+            Transition::Deliver.apply(local | Constraints::REASSEMBLY_PENDING),
+            Err(D7Error::IllegalTransition(_))
+        ));
+        assert_eq!(
+            Transition::Deliver.apply(local).unwrap(),
+            Constraints::empty()
+        );
+
+        let deleted = Transition::Delete.apply(local).unwrap();
+        assert_eq!(deleted, Constraints::DELETED);
+        assert!(Transition::Delete.apply(deleted).is_err());
+    }
+}
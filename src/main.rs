@@ -1,6 +1,13 @@
 use anyhow::Result;
+use bp7::administrative_record::NO_INFORMATION;
+use bp7::crc::{CrcRawType, CRC_16, CRC_32, CRC_NO};
+use bp7::flags::BundleControlFlags;
+use bp7::{Bundle, EndpointID};
 use clap::Parser;
-use d7sneakers::{Constraints, SneakerWorld};
+use d7sneakers::{BundleFilter, BundleFormat, Constraints, SendOptions, SneakerWorld, SyncPolicy};
+use std::convert::TryFrom;
+use std::io::Write;
+use std::time::Duration;
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
@@ -14,6 +21,9 @@ struct Opts {
     /// Sets base directory.
     #[clap(short, long, default_value = "/tmp/d7sneaker")]
     basedir: String,
+    /// Endpoint id of this node, enables status reports, e.g. dtn://node1/
+    #[clap(short, long)]
+    node: Option<String>,
     /// A level of verbosity, and can be used multiple times
     #[clap(short, long, parse(from_occurrences))]
     verbose: i32,
@@ -27,6 +37,13 @@ enum SubCommand {
     Add(Add),
     Sys(Sys),
     Query(Query),
+    Export(Export),
+    Merge(Merge),
+    Endpoint(Endpoint),
+    Send(Send),
+    Get(Get),
+    Rm(Rm),
+    Constraints(EditConstraints),
 }
 /// Add bundles in various forms
 #[derive(Parser)]
@@ -51,6 +68,205 @@ struct Sys {
     /// cleanup database
     #[clap(short, long)]
     db: bool,
+    /// list pending filesystem upgrade steps without applying them
+    #[clap(long)]
+    upgrade_dry_run: bool,
+    /// revert the last filesystem upgrade
+    #[clap(long)]
+    rollback: bool,
+    /// remove all bundles with exceeded lifetime
+    #[clap(short, long)]
+    gc: bool,
+    /// list bundles with exceeded lifetime without removing them
+    #[clap(long)]
+    gc_dry_run: bool,
+    /// reassemble all bundles whose fragments are complete
+    #[clap(long)]
+    reassemble: bool,
+    /// process all stored status reports
+    #[clap(long)]
+    status_reports: bool,
+}
+
+/// Filters selecting stored bundles
+#[derive(Parser)]
+struct Selection {
+    /// only bundles with either src or dst matching node query
+    #[clap(short = 'q', long)]
+    query_node: Option<String>,
+    /// only bundles with either src or dst service matching filter string
+    #[clap(short = 'F', long)]
+    filter_service: Option<String>,
+    /// only bundles with any of the given constraints, e.g. forward_pending,local_endpoint
+    #[clap(short, long)]
+    constraints: Option<Constraints>,
+}
+
+impl Selection {
+    fn filters(self) -> Vec<BundleFilter> {
+        let mut filters = Vec::new();
+        match (self.query_node, self.filter_service) {
+            (Some(node), Some(service)) => {
+                filters.push(BundleFilter::NodeAndService(node, service))
+            }
+            (Some(node), None) => filters.push(BundleFilter::Node(node)),
+            (None, Some(service)) => filters.push(BundleFilter::Service(service)),
+            (None, None) => {}
+        }
+        if let Some(constraints) = self.constraints {
+            filters.push(BundleFilter::Constraints(constraints));
+        }
+        filters
+    }
+    /// Returns the given bundle ids and the ones matching the filters, at least one must be given.
+    fn bids(self, sneakers: &SneakerWorld, mut bids: Vec<String>) -> Result<Vec<String>> {
+        let filters = self.filters();
+        if !filters.is_empty() {
+            for bid in sneakers.db.filter(&BundleFilter::And(filters))? {
+                if !bids.contains(&bid) {
+                    bids.push(bid);
+                }
+            }
+        } else if bids.is_empty() {
+            anyhow::bail!("no bundle ids or filters given");
+        }
+        Ok(bids)
+    }
+}
+
+/// Remove bundles from the store
+#[derive(Parser)]
+struct Rm {
+    /// bundle ids to remove
+    bids: Vec<String>,
+    #[clap(flatten)]
+    selection: Selection,
+    /// keep the ids as deleted and send deletion reports instead of forgetting the bundles
+    #[clap(short, long)]
+    delete: bool,
+    /// list the selected bundle ids without removing them
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// Change the constraints of bundles
+#[derive(Parser)]
+struct EditConstraints {
+    /// bundle ids to change
+    bids: Vec<String>,
+    #[clap(flatten)]
+    selection: Selection,
+    /// constraints to add, e.g. forward_pending,local_endpoint
+    #[clap(short, long, conflicts_with = "set")]
+    add: Option<Constraints>,
+    /// constraints to remove
+    #[clap(short, long, conflicts_with = "set")]
+    remove: Option<Constraints>,
+    /// replace the constraints, none clears them
+    #[clap(short, long)]
+    set: Option<Constraints>,
+}
+
+/// Export bundles to a directory, e.g., a sneakernet medium
+#[derive(Parser)]
+struct Export {
+    /// target directory, can be imported again with add --path
+    path: String,
+    #[clap(flatten)]
+    selection: Selection,
+    /// only bundles not yet delivered to this peer, exported bundles are marked as delivered
+    #[clap(short, long)]
+    peer: Option<String>,
+    /// split bundles bigger than this number of bytes into fragments
+    #[clap(short, long)]
+    mtu: Option<u64>,
+}
+
+/// Merge with another store, e.g., one carried on a USB stick
+#[derive(Parser)]
+struct Merge {
+    /// base directory of the other store
+    other: String,
+    /// only copy bundles from the other store into this one
+    #[clap(long, conflicts_with = "push-only")]
+    pull_only: bool,
+    /// only copy bundles from this store into the other one
+    #[clap(long)]
+    push_only: bool,
+}
+
+/// Create a bundle from a file and add it to the store
+#[derive(Parser)]
+struct Send {
+    /// source endpoint, e.g. dtn://me/chat
+    #[clap(short, long)]
+    src: String,
+    /// destination endpoint
+    #[clap(short, long)]
+    dst: String,
+    /// file containing the payload
+    #[clap(short, long)]
+    file: String,
+    /// lifetime in seconds
+    #[clap(short, long, default_value = "3600")]
+    lifetime: u64,
+    /// CRC type of all blocks: none, 16 or 32
+    #[clap(long, default_value = "32", parse(try_from_str = parse_crc))]
+    crc: CrcRawType,
+    /// endpoint for status reports, defaults to the source
+    #[clap(short, long)]
+    report_to: Option<String>,
+    /// request a status report on delivery
+    #[clap(long)]
+    report_delivery: bool,
+    /// forbid fragmentation of the bundle
+    #[clap(long)]
+    no_fragment: bool,
+}
+
+fn parse_crc(crc: &str) -> Result<CrcRawType> {
+    match crc {
+        "none" => Ok(CRC_NO),
+        "16" => Ok(CRC_16),
+        "32" => Ok(CRC_32),
+        _ => Err(anyhow::anyhow!("unknown CRC type {}", crc)),
+    }
+}
+
+/// Write a stored bundle or its payload to a file or stdout
+#[derive(Parser)]
+struct Get {
+    /// bundle id
+    bid: String,
+    /// only the payload, this is the default
+    #[clap(short, long, conflicts_with = "format")]
+    payload: bool,
+    /// output format: payload, cbor, hex or json
+    #[clap(short, long)]
+    format: Option<BundleFormat>,
+    /// output file, stdout if not given
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+/// Manage local endpoints and deliver their bundles
+#[derive(Parser)]
+struct Endpoint {
+    /// register a local endpoint, e.g. dtn://me/chat
+    #[clap(short, long)]
+    register: Option<String>,
+    /// unregister a local endpoint, its undelivered bundles are dispatched again
+    #[clap(short, long)]
+    unregister: Option<String>,
+    /// list all registered endpoints
+    #[clap(short, long)]
+    list: bool,
+    /// print all bundle IDs waiting for delivery to an endpoint
+    #[clap(short, long)]
+    pending: Option<String>,
+    /// print and mark delivered all bundle IDs waiting for delivery to an endpoint
+    #[clap(short, long)]
+    consume: Option<String>,
 }
 
 /// Query the database
@@ -89,6 +305,12 @@ struct Query {
     /// return a list of known group destinations matching a specific service
     #[clap(short, long)]
     group_destinations: Option<String>,
+    /// print all peers a bundle id was delivered to
+    #[clap(long)]
+    delivered_to: Option<String>,
+    /// print all bundle IDs not yet delivered to a peer
+    #[clap(long)]
+    pending_for: Option<String>,
 }
 fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
@@ -98,7 +320,7 @@ fn main() -> Result<()> {
     match opts.verbose {
         0 => std::env::set_var("RUST_LOG", ""),
         1 => std::env::set_var("RUST_LOG", "d7sneakers=info"),
-        2 | _ => std::env::set_var("RUST_LOG", "d7sneakers=debug"),
+        _ => std::env::set_var("RUST_LOG", "d7sneakers=debug"),
     }
     pretty_env_logger::init_timed();
 
@@ -106,19 +328,42 @@ fn main() -> Result<()> {
     // debug!("Value for config: {}", opts.config);
     debug!("Value for basedir: {}", opts.basedir);
 
+    // opening the store upgrades it, so these must be handled first
+    if let SubCommand::Sys(m) = &opts.subcmds {
+        if m.upgrade_dry_run {
+            let report = SneakerWorld::upgrade(&opts.basedir, true)?;
+            println!("{:#?}", report);
+            return Ok(());
+        } else if m.rollback {
+            SneakerWorld::rollback_upgrade(&opts.basedir)?;
+            return Ok(());
+        }
+    }
+
     let sneakers = SneakerWorld::open(&opts.basedir)?;
+    let sneakers = match &opts.node {
+        Some(node) => sneakers.with_node_id(EndpointID::try_from(node.as_str())?),
+        None => sneakers,
+    };
 
     match opts.subcmds {
         SubCommand::Add(a) => {
             if let Some(input) = a.hex {
-                let (bndl, bundle_size, path) = sneakers.fs.import_hex(&input)?;
-                sneakers.db.insert(&bndl, bundle_size, Some(path))?;
+                let mut bndl = Bundle::try_from(bp7::helpers::unhexify(&input)?)?;
+                sneakers.push(&mut bndl)?;
             } else if let Some(path) = a.path {
                 sneakers.import_dir(&path, a.recursive)?;
             }
         }
         SubCommand::Sys(m) => {
-            if m.db && m.fs {
+            if m.gc || m.gc_dry_run {
+                let report = sneakers.gc(m.gc_dry_run)?;
+                println!("{:#?}", report);
+            } else if m.reassemble {
+                println!("{:#?}", sneakers.reassemble_all()?);
+            } else if m.status_reports {
+                println!("{:#?}", sneakers.process_status_reports()?);
+            } else if m.db && m.fs {
                 sneakers.sync()?;
             } else if m.db {
                 sneakers.db.sync_with_fs(&sneakers.fs)?;
@@ -126,57 +371,218 @@ fn main() -> Result<()> {
                 sneakers.fs.sync_to_db(&sneakers.db)?;
             }
         }
+        SubCommand::Export(e) => {
+            let mut filters = e.selection.filters();
+            if let Some(peer) = &e.peer {
+                filters.push(BundleFilter::NotDeliveredTo(peer.clone()));
+            }
+            let filter = if filters.is_empty() {
+                BundleFilter::All
+            } else {
+                BundleFilter::And(filters)
+            };
+            let sneakers = match e.mtu {
+                Some(mtu) => sneakers.with_mtu(mtu),
+                None => sneakers,
+            };
+            let exported = sneakers.export_dir(&e.path, &filter)?;
+            if let Some(peer) = &e.peer {
+                for bid in &exported {
+                    sneakers.mark_forwarded(bid, peer, Some("export"))?;
+                }
+            }
+            println!("{:#?}", exported);
+        }
+        SubCommand::Merge(m) => {
+            let policy = match (m.pull_only, m.push_only) {
+                (true, _) => SyncPolicy::Pull,
+                (_, true) => SyncPolicy::Push,
+                _ => SyncPolicy::Both,
+            };
+            let other = SneakerWorld::open(&m.other)?;
+            println!("{:#?}", sneakers.sync_with(&other, policy)?);
+        }
+        SubCommand::Endpoint(e) => {
+            if let Some(eid) = e.register {
+                let dispatched =
+                    sneakers.register_endpoint(&EndpointID::try_from(eid.as_str())?)?;
+                println!("{:#?}", dispatched);
+            } else if let Some(eid) = e.unregister {
+                println!("{:#?}", sneakers.unregister_endpoint(&eid)?);
+            } else if e.list {
+                println!("{:#?}", sneakers.db.endpoints()?);
+            } else if let Some(eid) = e.pending {
+                println!("{:#?}", sneakers.pending_deliveries(&eid)?);
+            } else if let Some(eid) = e.consume {
+                let bids: Vec<String> = sneakers.consume(&eid)?.iter().map(|b| b.id()).collect();
+                println!("{:#?}", bids);
+            }
+        }
+        SubCommand::Send(s) => {
+            let mut flags = BundleControlFlags::empty();
+            if s.report_delivery {
+                flags |= BundleControlFlags::BUNDLE_STATUS_REQUEST_DELIVERY;
+            }
+            if s.no_fragment {
+                flags |= BundleControlFlags::BUNDLE_MUST_NOT_FRAGMENTED;
+            }
+            let mut options = SendOptions::default()
+                .lifetime(Duration::from_secs(s.lifetime))
+                .flags(flags)
+                .crc(s.crc);
+            if let Some(report_to) = s.report_to {
+                options = options.report_to(EndpointID::try_from(report_to.as_str())?);
+            }
+            let bid = sneakers.send(
+                &EndpointID::try_from(s.src.as_str())?,
+                &EndpointID::try_from(s.dst.as_str())?,
+                std::fs::read(&s.file)?,
+                &options,
+            )?;
+            println!("{}", bid);
+        }
+        SubCommand::Get(g) => {
+            let format = match g.format {
+                Some(format) if !g.payload => format,
+                _ => BundleFormat::Payload,
+            };
+            let data = sneakers.get_encoded(&g.bid, format)?;
+            match g.output {
+                Some(path) => std::fs::write(path, data)?,
+                None => std::io::stdout().write_all(&data)?,
+            }
+        }
+        SubCommand::Rm(r) => {
+            let bids = r.selection.bids(&sneakers, r.bids)?;
+            if r.dry_run {
+                println!("{:#?}", bids);
+                return Ok(());
+            }
+            let mut removed = Vec::new();
+            let mut failed = 0;
+            for bid in bids {
+                let res = if r.delete {
+                    sneakers.delete(&bid, NO_INFORMATION)
+                } else {
+                    sneakers.remove(&bid)
+                };
+                match res {
+                    Ok(()) => removed.push(bid),
+                    Err(err) => {
+                        error!("could not remove {}: {}", bid, err);
+                        failed += 1;
+                    }
+                }
+            }
+            println!("{:#?}", removed);
+            if failed > 0 {
+                anyhow::bail!("{} bundles could not be removed", failed);
+            }
+        }
+        SubCommand::Constraints(c) => {
+            // only deleting removes the file, which is what `rm --delete` does
+            if [c.set, c.add]
+                .iter()
+                .flatten()
+                .any(|constraints| constraints.contains(Constraints::DELETED))
+            {
+                anyhow::bail!("DELETED cannot be set here, use rm --delete instead");
+            }
+            let (set, add, remove) = (c.set, c.add, c.remove);
+            let bids = c.selection.bids(&sneakers, c.bids)?;
+            let mut changed = Vec::new();
+            let mut failed = 0;
+            for bid in bids {
+                let edit = || -> d7sneakers::Result<Option<Constraints>> {
+                    // deleted bundles have no file anymore
+                    if sneakers
+                        .db
+                        .get_constraints(&bid)?
+                        .contains(Constraints::DELETED)
+                    {
+                        warn!("skipping deleted bundle {}", bid);
+                        return Ok(None);
+                    }
+                    if let Some(constraints) = set {
+                        sneakers.db.set_constraints(&bid, constraints)?;
+                    }
+                    if let Some(constraints) = add {
+                        sneakers.db.add_constraints(&bid, constraints)?;
+                    }
+                    if let Some(constraints) = remove {
+                        sneakers.db.remove_constraints(&bid, constraints)?;
+                    }
+                    Ok(Some(sneakers.db.get_constraints(&bid)?))
+                };
+                match edit() {
+                    Ok(Some(constraints)) => changed.push((bid, constraints)),
+                    Ok(None) => {}
+                    Err(err) => {
+                        error!("could not change constraints of {}: {}", bid, err);
+                        failed += 1;
+                    }
+                }
+            }
+            println!("{:#?}", changed);
+            if failed > 0 {
+                anyhow::bail!("constraints of {} bundles could not be changed", failed);
+            }
+        }
         SubCommand::Query(q) => {
             if q.ids {
-                println!("{:#?}", sneakers.db.ids());
+                println!("{:#?}", sneakers.db.ids()?);
             } else if q.print_infos.is_some() {
                 let bid = q.print_infos.unwrap();
-                println!("{:#?}", sneakers.db.get_bundle_entry(&bid).unwrap());
-                println!("{:#?}", sneakers.db.get_constraints(&bid).unwrap());
+                println!("{:#?}", sneakers.db.get_bundle_entry(&bid)?);
+                println!("{:#?}", sneakers.db.get_constraints(&bid)?);
             } else if q.all_constraints {
-                println!("{:#?}", sneakers.db.all_constraints());
+                println!("{:#?}", sneakers.db.all_constraints()?);
             } else if q.forward {
                 println!(
                     "{:#?}",
-                    sneakers.db.filter_constraints(Constraints::FORWARD_PENDING)
+                    sneakers.db.filter_constraints(Constraints::FORWARD_PENDING)?
                 );
             } else if q.dispatch {
                 println!(
                     "{:#?}",
                     sneakers
                         .db
-                        .filter_constraints(Constraints::DISPATCH_PENDING)
+                        .filter_constraints(Constraints::DISPATCH_PENDING)?
                 );
             } else if q.reassembly {
                 println!(
                     "{:#?}",
                     sneakers
                         .db
-                        .filter_constraints(Constraints::REASSEMBLY_PENDING)
+                        .filter_constraints(Constraints::REASSEMBLY_PENDING)?
                 );
             } else if q.contra {
                 println!(
                     "{:#?}",
-                    sneakers.db.filter_constraints(Constraints::CONTRAINDICATED)
+                    sneakers.db.filter_constraints(Constraints::CONTRAINDICATED)?
                 );
             } else if q.local {
                 println!(
                     "{:#?}",
-                    sneakers.db.filter_constraints(Constraints::LOCAL_ENDPOINT)
+                    sneakers.db.filter_constraints(Constraints::LOCAL_ENDPOINT)?
                 );
             } else if let Some(node) = q.query_node {
                 if let Some(service) = q.filter_service {
                     println!(
                         "{:#?}",
-                        sneakers.db.filter_node_and_service(&node, &service)
+                        sneakers.db.filter_node_and_service(&node, &service)?
                     );
                 } else {
-                    println!("{:#?}", sneakers.db.filter_node(&node));
+                    println!("{:#?}", sneakers.db.filter_node(&node)?);
                 }
             } else if let Some(service) = q.filter_service {
-                println!("{:#?}", sneakers.db.filter_service(&service));
+                println!("{:#?}", sneakers.db.filter_service(&service)?);
             } else if let Some(service) = q.group_destinations {
-                println!("{:#?}", sneakers.db.filter_groups(&service));
+                println!("{:#?}", sneakers.db.filter_groups(&service)?);
+            } else if let Some(bid) = q.delivered_to {
+                println!("{:#?}", sneakers.db.delivered_to(&bid)?);
+            } else if let Some(peer) = q.pending_for {
+                println!("{:#?}", sneakers.db.pending_for_peer(&peer)?);
             }
         }
     }
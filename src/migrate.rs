@@ -0,0 +1,303 @@
+//! Step-wise upgrades of the on-disk layout of a store.
+//!
+//! The layout version is kept in `files/version.txt`. Every [`Migration`]
+//! lifts a store by exactly one version, so old stores are upgraded by running
+//! all steps from their version up to [`crate::D7S_VERSION`] in order.
+//!
+//! Before the first step is applied, the database and the old version are
+//! copied to `upgrade-backup/` and every file rename is logged there. A failed
+//! upgrade is rolled back automatically, a successful one can be reverted with
+//! [`rollback`] until the next upgrade replaces the backup.
+
+use bp7::Bundle;
+use log::{debug, info, warn};
+use rusqlite::{params, Connection, ToSql};
+use std::convert::TryInto;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::error::{D7Error, Result};
+use crate::fs::encode_bid;
+
+const BACKUP_DIR: &str = "upgrade-backup";
+const RENAME_LOG: &str = "renames.txt";
+
+struct Migration {
+    /// version this step upgrades from, the result is `from + 1`
+    from: u32,
+    description: &'static str,
+    run: fn(&mut Migrator) -> Result<()>,
+}
+
+/// All known upgrade steps, ordered by version.
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "rename bundle files to lossless bundle ID encoding",
+    run: v1_lossless_filenames,
+}];
+
+/// Summary of an upgrade, lists the performed or, in dry-run mode, the planned actions.
+#[derive(Debug, Clone, Default)]
+pub struct UpgradeReport {
+    pub from: u32,
+    pub to: u32,
+    pub dry_run: bool,
+    pub actions: Vec<String>,
+}
+
+/// Context handed to each migration step.
+///
+/// All modifications must go through it so that they are journaled and
+/// skipped in dry-run mode.
+struct Migrator {
+    files: PathBuf,
+    dry_run: bool,
+    conn: Option<Connection>,
+    rename_log: Option<fs::File>,
+    actions: Vec<String>,
+}
+
+impl Migrator {
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        self.actions
+            .push(format!("rename {} -> {}", from.display(), to.display()));
+        if self.dry_run {
+            return Ok(());
+        }
+        // log first, rollback skips renames that never happened
+        if let Some(log) = self.rename_log.as_mut() {
+            writeln!(log, "{}\t{}", from.display(), to.display())?;
+            log.sync_data()?;
+        }
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(from, to)?;
+        Ok(())
+    }
+    fn execute(&mut self, sql: &str, params: &[&dyn ToSql]) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        if let Some(conn) = &self.conn {
+            conn.execute(sql, params)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the layout version of the store at `basepath`, `None` for new stores.
+pub fn fs_version(basepath: &str) -> Result<Option<u32>> {
+    let version_file = Path::new(basepath).join("files").join("version.txt");
+    if version_file.exists() {
+        let version = fs::read_to_string(version_file)?
+            .trim()
+            .parse()
+            .map_err(|_| D7Error::Corrupt("invalid version.txt".into()))?;
+        Ok(Some(version))
+    } else {
+        Ok(None)
+    }
+}
+
+fn write_fs_version(basepath: &Path, version: u32) -> Result<()> {
+    fs::write(
+        basepath.join("files").join("version.txt"),
+        format!("{}", version),
+    )?;
+    Ok(())
+}
+
+/// Upgrades the store at `basepath` to the current layout version.
+pub fn upgrade(basepath: &str, dry_run: bool) -> Result<UpgradeReport> {
+    let mut report = UpgradeReport {
+        from: crate::D7S_VERSION,
+        to: crate::D7S_VERSION,
+        dry_run,
+        actions: Vec::new(),
+    };
+    let version = match fs_version(basepath)? {
+        Some(version) => version,
+        None => return Ok(report),
+    };
+    report.from = version;
+    if version > crate::D7S_VERSION {
+        return Err(D7Error::Outdated(format!(
+            "filesystem structure version {} is newer than supported version {}",
+            version,
+            crate::D7S_VERSION
+        )));
+    }
+    if version == crate::D7S_VERSION {
+        return Ok(report);
+    }
+    info!(
+        "upgrading filesystem structure from version {} to {} (dry run: {})",
+        version,
+        crate::D7S_VERSION,
+        dry_run
+    );
+
+    let base = Path::new(basepath);
+    let db_file = base.join("db.sqlite3");
+    let mut migrator = Migrator {
+        files: base.join("files"),
+        dry_run,
+        conn: None,
+        rename_log: None,
+        actions: Vec::new(),
+    };
+    if !dry_run {
+        let backup = base.join(BACKUP_DIR);
+        if backup.exists() {
+            fs::remove_dir_all(&backup)?;
+        }
+        fs::create_dir_all(&backup)?;
+        if db_file.exists() {
+            let conn = Connection::open(&db_file)?;
+            // move committed transactions from the WAL into the database file before copying it
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+            fs::copy(&db_file, backup.join("db.sqlite3"))?;
+            migrator.conn = Some(conn);
+        }
+        fs::write(backup.join("version.txt"), format!("{}", version))?;
+        migrator.rename_log = Some(fs::File::create(backup.join(RENAME_LOG))?);
+    }
+
+    for v in version..crate::D7S_VERSION {
+        let step = match MIGRATIONS.iter().find(|m| m.from == v) {
+            Some(step) => step,
+            None => {
+                return Err(D7Error::Outdated(format!(
+                    "no upgrade path from filesystem version {}",
+                    v
+                )))
+            }
+        };
+        info!("upgrade {} -> {}: {}", v, v + 1, step.description);
+        migrator
+            .actions
+            .push(format!("upgrade {} -> {}: {}", v, v + 1, step.description));
+        let res = (step.run)(&mut migrator)
+            .and_then(|_| if dry_run { Ok(()) } else { write_fs_version(base, v + 1) });
+        if let Err(err) = res {
+            if !dry_run {
+                warn!("upgrade failed, rolling back: {}", err);
+                drop(migrator);
+                rollback(basepath)?;
+            }
+            return Err(err);
+        }
+    }
+    report.to = crate::D7S_VERSION;
+    report.actions = migrator.actions;
+    Ok(report)
+}
+
+/// Reverts the last upgrade of the store at `basepath` using its backup.
+pub fn rollback(basepath: &str) -> Result<()> {
+    let base = Path::new(basepath);
+    let backup = base.join(BACKUP_DIR);
+    if !backup.exists() {
+        return Err(D7Error::NotFound("upgrade backup".into()));
+    }
+    let renames = fs::read_to_string(backup.join(RENAME_LOG)).unwrap_or_default();
+    for line in renames.lines().rev() {
+        if let Some((from, to)) = line.split_once('\t') {
+            if Path::new(to).exists() {
+                debug!("restoring {}", from);
+                fs::rename(to, from)?;
+            }
+        }
+    }
+    let db_backup = backup.join("db.sqlite3");
+    if db_backup.exists() {
+        for suffix in ["-wal", "-shm"] {
+            let _ = fs::remove_file(base.join(format!("db.sqlite3{}", suffix)));
+        }
+        fs::copy(&db_backup, base.join("db.sqlite3"))?;
+    }
+    let version: u32 = fs::read_to_string(backup.join("version.txt"))?
+        .trim()
+        .parse()
+        .map_err(|_| D7Error::Corrupt("invalid version.txt in upgrade backup".into()))?;
+    write_fs_version(base, version)?;
+    fs::remove_dir_all(&backup)?;
+    info!("restored filesystem structure version {}", version);
+    Ok(())
+}
+
+/// Version 1 derived file names with `sanitize`, which is lossy.
+fn v1_lossless_filenames(m: &mut Migrator) -> Result<()> {
+    let files: Vec<PathBuf> = WalkDir::new(&m.files)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|f| {
+            f.file_name()
+                .to_str()
+                .unwrap_or_default()
+                .ends_with(".bundle")
+        })
+        .map(|entry| entry.into_path())
+        .collect();
+    for path in files {
+        // the old names are lossy, the bundle itself is the only reliable source of its ID
+        let bndl: Bundle = match fs::read(&path)?.try_into() {
+            Ok(bndl) => bndl,
+            Err(_) => {
+                warn!("could not parse bundle file: {}", path.display());
+                continue;
+            }
+        };
+        let bid = bndl.id();
+        let dest_path = path.with_file_name(format!("{}.bundle", encode_bid(&bid)));
+        if dest_path != path {
+            m.rename(&path, &dest_path)?;
+            m.execute(
+                "UPDATE bids SET path = ?1 WHERE bid = ?2",
+                params![dest_path.to_string_lossy(), bid],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fs_version, rollback, upgrade};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn upgrade_v1_test() {
+        let tmp = crate::fs::TempDir::new().unwrap();
+        let base = tmp.path().to_str().unwrap();
+        let dir = Path::new(base).join("files").join("single").join("node2");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(Path::new(base).join("files").join("version.txt"), "1").unwrap();
+        let src = bp7::EndpointID::with_dtn("//node_1/x_y").unwrap();
+        let dst = bp7::EndpointID::with_dtn("//node2/inbox").unwrap();
+        let mut bndl = bp7::bundle::new_std_payload_bundle(src, dst, b"ABC".to_vec());
+        let legacy = dir.join("dtn_node_1_x_y-0-0.bundle");
+        let encoded = dir.join(format!("{}.bundle", crate::encode_bid(&bndl.id())));
+        fs::write(&legacy, bndl.to_cbor()).unwrap();
+
+        let report = upgrade(base, true).unwrap();
+        assert_eq!(report.to, crate::D7S_VERSION);
+        assert!(report.actions.len() > 1);
+        assert!(legacy.exists());
+        assert_eq!(fs_version(base).unwrap(), Some(1));
+
+        upgrade(base, false).unwrap();
+        assert!(!legacy.exists());
+        assert!(encoded.exists());
+        assert_eq!(fs_version(base).unwrap(), Some(crate::D7S_VERSION));
+
+        rollback(base).unwrap();
+        assert!(legacy.exists());
+        assert!(!encoded.exists());
+        assert_eq!(fs_version(base).unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,208 @@
+//! Storage limits and the policies deciding which bundles make room for new ones.
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::Constraints;
+
+/// Stored bundle that may be evicted to stay within the quota.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionCandidate {
+    pub bid: String,
+    pub size: u64,
+    /// unix time in milliseconds
+    pub time_added_to_db: u64,
+    /// end of lifetime in milliseconds since the DTN epoch
+    pub expires: u64,
+    pub constraints: Constraints,
+}
+
+/// Decides which bundles are removed first when a quota is exceeded.
+pub trait EvictionPolicy: Debug + Send + Sync {
+    /// Returns false if the bundle must never be evicted.
+    fn evictable(&self, _candidate: &EvictionCandidate) -> bool {
+        true
+    }
+    /// Sorts the candidates so that the bundle to evict first comes first.
+    fn order(&self, candidates: &mut [EvictionCandidate]);
+}
+
+/// Evicts the bundles that were added to the store first.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OldestFirst;
+
+impl EvictionPolicy for OldestFirst {
+    fn order(&self, candidates: &mut [EvictionCandidate]) {
+        candidates.sort_by_key(|c| c.time_added_to_db);
+    }
+}
+
+/// Evicts the bundles whose lifetime ends first.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SoonestExpiry;
+
+impl EvictionPolicy for SoonestExpiry {
+    fn order(&self, candidates: &mut [EvictionCandidate]) {
+        candidates.sort_by_key(|c| c.expires);
+    }
+}
+
+/// Evicts the biggest bundles first.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestFirst;
+
+impl EvictionPolicy for LargestFirst {
+    fn order(&self, candidates: &mut [EvictionCandidate]) {
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.size));
+    }
+}
+
+/// Uses the order of the wrapped policy but never evicts bundles for local endpoints.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeepLocal<P>(pub P);
+
+impl<P: EvictionPolicy> EvictionPolicy for KeepLocal<P> {
+    fn evictable(&self, candidate: &EvictionCandidate) -> bool {
+        !candidate.constraints.contains(Constraints::LOCAL_ENDPOINT) && self.0.evictable(candidate)
+    }
+    fn order(&self, candidates: &mut [EvictionCandidate]) {
+        self.0.order(candidates);
+    }
+}
+
+/// Limits for the total size and number of stored bundles.
+///
+/// Without limits nothing is ever evicted.
+#[derive(Debug, Clone)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_bundles: Option<u64>,
+    pub policy: Arc<dyn EvictionPolicy>,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+impl Quota {
+    pub fn unlimited() -> Self {
+        Self {
+            max_bytes: None,
+            max_bundles: None,
+            policy: Arc::new(KeepLocal(OldestFirst)),
+        }
+    }
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+    pub fn max_bundles(mut self, max_bundles: u64) -> Self {
+        self.max_bundles = Some(max_bundles);
+        self
+    }
+    pub fn policy<P: EvictionPolicy + 'static>(mut self, policy: P) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_bundles.is_none()
+    }
+    /// Selects the bundles to evict so that `bytes` and `bundles` more fit into the store.
+    ///
+    /// `used_bytes` and `used_bundles` describe the current usage, returns `None`
+    /// if the policy does not allow to free enough space.
+    pub fn plan(
+        &self,
+        used_bytes: u64,
+        used_bundles: u64,
+        bytes: u64,
+        bundles: u64,
+        mut candidates: Vec<EvictionCandidate>,
+    ) -> Option<Vec<EvictionCandidate>> {
+        let fits = |used_bytes: u64, used_bundles: u64| {
+            self.max_bytes.is_none_or(|max| used_bytes + bytes <= max)
+                && self
+                    .max_bundles
+                    .is_none_or(|max| used_bundles + bundles <= max)
+        };
+        let (mut used_bytes, mut used_bundles) = (used_bytes, used_bundles);
+        let mut evict = Vec::new();
+        if fits(used_bytes, used_bundles) {
+            return Some(evict);
+        }
+        candidates.retain(|c| self.policy.evictable(c));
+        self.policy.order(&mut candidates);
+        for candidate in candidates {
+            used_bytes = used_bytes.saturating_sub(candidate.size);
+            used_bundles = used_bundles.saturating_sub(1);
+            evict.push(candidate);
+            if fits(used_bytes, used_bundles) {
+                return Some(evict);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(bid: &str, size: u64, added: u64, expires: u64) -> EvictionCandidate {
+        EvictionCandidate {
+            bid: bid.into(),
+            size,
+            time_added_to_db: added,
+            expires,
+            constraints: Constraints::empty(),
+        }
+    }
+
+    #[test]
+    fn eviction_policy_test() {
+        let mut local = candidate("local", 100, 0, 0);
+        local.constraints = Constraints::LOCAL_ENDPOINT;
+        let candidates = vec![
+            local,
+            candidate("old", 10, 1, 30),
+            candidate("big", 50, 2, 20),
+            candidate("expiring", 20, 3, 10),
+        ];
+        let bids = |plan: Option<Vec<EvictionCandidate>>| {
+            plan.map(|plan| plan.into_iter().map(|c| c.bid).collect::<Vec<_>>())
+        };
+
+        let quota = Quota::unlimited().max_bytes(200);
+        assert_eq!(
+            bids(quota.plan(180, 4, 20, 1, candidates.clone())),
+            Some(vec![])
+        );
+        assert_eq!(
+            bids(quota.plan(180, 4, 40, 1, candidates.clone())),
+            Some(vec!["old".into(), "big".into()])
+        );
+        let quota = quota.policy(KeepLocal(SoonestExpiry));
+        assert_eq!(
+            bids(quota.plan(180, 4, 40, 1, candidates.clone())),
+            Some(vec!["expiring".into()])
+        );
+        let quota = quota.policy(KeepLocal(LargestFirst));
+        assert_eq!(
+            bids(quota.plan(180, 4, 40, 1, candidates.clone())),
+            Some(vec!["big".into()])
+        );
+        assert_eq!(bids(quota.plan(180, 4, 120, 1, candidates.clone())), None);
+        let quota = quota.policy(LargestFirst);
+        assert_eq!(
+            bids(quota.plan(180, 4, 120, 1, candidates.clone())),
+            Some(vec!["local".into()])
+        );
+
+        let quota = Quota::unlimited().max_bundles(4);
+        assert_eq!(
+            bids(quota.plan(180, 4, 0, 1, candidates)),
+            Some(vec!["old".into()])
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Options for bundles created by local applications, see [`crate::SneakerWorld::send`].
+use std::time::Duration;
+
+use bp7::crc::{CrcRawType, CRC_32};
+use bp7::flags::{BlockControlFlags, BundleControlFlags};
+use bp7::{Bundle, CreationTimestamp, EndpointID};
+
+use crate::error::{D7Error, Result};
+
+/// Lifetime, flags and CRC type of a new bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendOptions {
+    pub lifetime: Duration,
+    /// bundle processing control flags as in [`bp7::primary::PrimaryBlock`]
+    pub flags: u64,
+    pub crc: CrcRawType,
+    /// defaults to the source endpoint
+    pub report_to: Option<EndpointID>,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::from_secs(60 * 60),
+            flags: 0,
+            crc: CRC_32,
+            report_to: None,
+        }
+    }
+}
+
+impl SendOptions {
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+    pub fn flags(mut self, flags: BundleControlFlags) -> Self {
+        self.flags = flags.bits();
+        self
+    }
+    pub fn crc(mut self, crc: CrcRawType) -> Self {
+        self.crc = crc;
+        self
+    }
+    pub fn report_to(mut self, report_to: EndpointID) -> Self {
+        self.report_to = Some(report_to);
+        self
+    }
+    /// Rejects flags reserved for the store and a zero lifetime.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let reserved = (BundleControlFlags::BUNDLE_IS_FRAGMENT
+            | BundleControlFlags::BUNDLE_ADMINISTRATIVE_RECORD_PAYLOAD)
+            .bits();
+        if self.flags & reserved != 0 {
+            return Err(D7Error::Parse(format!(
+                "flags {:#x} cannot be set for new bundles",
+                self.flags & reserved
+            )));
+        }
+        if self.lifetime.is_zero() {
+            return Err(D7Error::Parse("lifetime must not be zero".into()));
+        }
+        Ok(())
+    }
+    /// Creates the bundle with a payload block, the options are validated first.
+    pub(crate) fn build(
+        &self,
+        src: &EndpointID,
+        dst: &EndpointID,
+        payload: Vec<u8>,
+        timestamp: CreationTimestamp,
+    ) -> Result<Bundle> {
+        self.validate()?;
+        let primary = bp7::primary::PrimaryBlockBuilder::default()
+            .destination(dst.clone())
+            .source(src.clone())
+            .report_to(self.report_to.clone().unwrap_or_else(|| src.clone()))
+            .bundle_control_flags(self.flags)
+            .creation_timestamp(timestamp)
+            .lifetime(self.lifetime)
+            .build()
+            .map_err(|err| D7Error::Parse(err.to_string()))?;
+        let mut bndl = Bundle::new(
+            primary,
+            vec![bp7::canonical::new_payload_block(
+                BlockControlFlags::empty(),
+                payload,
+            )],
+        );
+        bndl.set_crc(self.crc);
+        Ok(bndl)
+    }
+}
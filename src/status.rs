@@ -0,0 +1,150 @@
+//! Bundle status reports as defined in RFC 9171 section 6.1.1.
+use bp7::administrative_record::{
+    AdministrativeRecord, BundleStatusItem, StatusInformationPos, StatusReport, StatusReportReason,
+    DELETED_BUNDLE, DELIVERED_BUNDLE, FORWARDED_BUNDLE, MAX_STATUS_INFORMATION_POS,
+    RECEIVED_BUNDLE,
+};
+use bp7::flags::{BundleControlFlags, BundleValidation};
+use bp7::{Bundle, CreationTimestamp, EndpointID};
+
+use crate::error::{D7Error, Result};
+
+/// Returns true if the bundle asks for a report of the given status.
+///
+/// Administrative records and bundles without report-to endpoint never get reports.
+pub fn is_requested(bndl: &Bundle, status: StatusInformationPos) -> bool {
+    let flags = bndl.primary.bundle_control_flags.flags();
+    let flag = match status {
+        RECEIVED_BUNDLE => BundleControlFlags::BUNDLE_STATUS_REQUEST_RECEPTION,
+        FORWARDED_BUNDLE => BundleControlFlags::BUNDLE_STATUS_REQUEST_FORWARD,
+        DELIVERED_BUNDLE => BundleControlFlags::BUNDLE_STATUS_REQUEST_DELIVERY,
+        DELETED_BUNDLE => BundleControlFlags::BUNDLE_STATUS_REQUEST_DELETION,
+        _ => return false,
+    };
+    flags.contains(flag)
+        && !bndl.is_administrative_record()
+        && bndl.primary.report_to != EndpointID::none()
+}
+
+/// Creates a status report bundle from `node` to the report-to endpoint of `bndl`.
+///
+/// Unlike [`bp7::administrative_record::new_status_report_bundle`] fragments are supported.
+pub fn new_report_bundle(
+    bndl: &Bundle,
+    node: &EndpointID,
+    status: StatusInformationPos,
+    reason: StatusReportReason,
+) -> Bundle {
+    let with_time = bndl
+        .primary
+        .bundle_control_flags
+        .flags()
+        .contains(BundleControlFlags::BUNDLE_REQUEST_STATUS_TIME);
+    let status_information = (0..MAX_STATUS_INFORMATION_POS)
+        .map(|pos| BundleStatusItem {
+            asserted: pos == status,
+            time: if pos == status && with_time {
+                bp7::dtn_time_now()
+            } else {
+                0
+            },
+            status_requested: pos == status && with_time,
+        })
+        .collect();
+    let (frag_offset, frag_len) = if bndl.primary.has_fragmentation() {
+        (
+            bndl.primary.fragmentation_offset,
+            bndl.payload().map_or(0, |p| p.len()) as u64,
+        )
+    } else {
+        (0, 0)
+    };
+    let record = AdministrativeRecord::BundleStatusReport(StatusReport {
+        status_information,
+        report_reason: reason,
+        source_node: bndl.primary.source.clone(),
+        timestamp: bndl.primary.creation_timestamp.clone(),
+        frag_offset,
+        frag_len,
+    });
+
+    let primary = bp7::primary::PrimaryBlockBuilder::default()
+        .destination(bndl.primary.report_to.clone())
+        .source(node.clone())
+        .report_to(node.clone())
+        .bundle_control_flags(BundleControlFlags::BUNDLE_ADMINISTRATIVE_RECORD_PAYLOAD.bits())
+        .creation_timestamp(CreationTimestamp::now())
+        .lifetime(bndl.primary.lifetime)
+        .build()
+        .expect("primary block with all fields");
+    let mut report = bp7::bundle::BundleBuilder::default()
+        .primary(primary)
+        .canonicals(vec![record.to_payload()])
+        .build()
+        .expect("bundle with primary and payload block");
+    report.set_crc(bp7::crc::CRC_NO);
+    report
+}
+
+/// Decodes the status report carried by an administrative record.
+///
+/// Returns `None` for other bundles and other kinds of administrative records.
+pub fn parse_report(bndl: &Bundle) -> Result<Option<StatusReport>> {
+    if !bndl.is_administrative_record() {
+        return Ok(None);
+    }
+    let payload = bndl
+        .payload()
+        .ok_or_else(|| D7Error::Parse(format!("{} has no payload", bndl.id())))?;
+    let record: AdministrativeRecord =
+        serde_cbor::from_slice(payload).map_err(|err| D7Error::Parse(err.to_string()))?;
+    match record {
+        AdministrativeRecord::BundleStatusReport(report) => Ok(Some(report)),
+        _ => Ok(None),
+    }
+}
+
+/// Returns the asserted status positions of a report.
+pub fn asserted(report: &StatusReport) -> Vec<StatusInformationPos> {
+    report
+        .status_information
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.asserted)
+        .map(|(pos, _)| pos as StatusInformationPos)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bp7::administrative_record::NO_INFORMATION;
+    use std::convert::TryInto;
+
+    #[test]
+    fn status_report_test() {
+        let mut bndl = bp7::helpers::rnd_bundle(bp7::CreationTimestamp::now());
+        let node = EndpointID::with_dtn("//reporter/").unwrap();
+        assert!(is_requested(&bndl, DELIVERED_BUNDLE));
+        assert!(!is_requested(&bndl, RECEIVED_BUNDLE));
+
+        let mut report = new_report_bundle(&bndl, &node, DELIVERED_BUNDLE, NO_INFORMATION);
+        assert_eq!(report.primary.destination, bndl.primary.report_to);
+        assert!(!is_requested(&report, DELIVERED_BUNDLE));
+        let decoded: Bundle = report.to_cbor().try_into().unwrap();
+        let sr = parse_report(&decoded).unwrap().unwrap();
+        assert_eq!(sr.refbundle(), bndl.id());
+        assert_eq!(asserted(&sr), vec![DELIVERED_BUNDLE]);
+        assert_eq!(parse_report(&bndl).unwrap(), None);
+
+        let fragment =
+            crate::fragment::tests::split(&crate::fragment::tests::big_bundle(10), 4).remove(1);
+        let report = new_report_bundle(&fragment, &node, DELETED_BUNDLE, NO_INFORMATION);
+        assert_eq!(
+            parse_report(&report).unwrap().unwrap().refbundle(),
+            fragment.id()
+        );
+        bndl.primary.report_to = EndpointID::none();
+        assert!(!is_requested(&bndl, DELIVERED_BUNDLE));
+    }
+}
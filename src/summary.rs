@@ -0,0 +1,125 @@
+//! Compact summaries of the stored bundle ids for anti-entropy between peers.
+//!
+//! A summary is a Bloom filter: bundles that are not contained in a remote
+//! summary are definitely missing on the remote, bundles that are contained
+//! are only likely known to it.
+use crate::error::{D7Error, Result};
+
+/// Version of the serialised format produced by [`SummaryVector::to_bytes`].
+const SUMMARY_FORMAT: u8 = 1;
+
+/// False positive rate used for the summaries of a store.
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// 64 bit FNV-1a, stable across platforms and versions unlike the std hashers.
+fn fnv1a(data: &[u8], offset: u64) -> u64 {
+    data.iter().fold(offset, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Bloom filter over bundle ids.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SummaryVector {
+    num_hashes: u8,
+    num_bits: u32,
+    bits: Vec<u8>,
+}
+
+impl SummaryVector {
+    /// Creates an empty summary sized for `items` bundle ids at the given false positive rate.
+    pub fn with_capacity(items: usize, false_positive_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-items * rate.ln() / (ln2 * ln2))
+            .ceil()
+            .clamp(64.0, u32::MAX as f64);
+        let num_hashes = (num_bits / items * ln2).round().clamp(1.0, 32.0);
+        Self {
+            num_hashes: num_hashes as u8,
+            num_bits: num_bits as u32,
+            bits: vec![0; (num_bits as usize).div_ceil(8)],
+        }
+    }
+    /// Bit positions of a bundle id using double hashing.
+    fn positions<'a>(&'a self, bid: &str) -> impl Iterator<Item = usize> + 'a {
+        let h1 = fnv1a(bid.as_bytes(), 0xcbf2_9ce4_8422_2325);
+        let h2 = fnv1a(bid.as_bytes(), 0x8422_2325_cbf2_9ce4) | 1;
+        let num_bits = u64::from(self.num_bits);
+        (0..u64::from(self.num_hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+    pub fn insert(&mut self, bid: &str) {
+        let positions: Vec<usize> = self.positions(bid).collect();
+        for pos in positions {
+            self.bits[pos / 8] |= 1 << (pos % 8);
+        }
+    }
+    /// Returns false if the bundle id was definitely not inserted.
+    pub fn contains(&self, bid: &str) -> bool {
+        self.positions(bid)
+            .all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+    /// Size of the serialised summary in bytes.
+    pub fn encoded_len(&self) -> usize {
+        6 + self.bits.len()
+    }
+    /// Returns true if no bundle id was inserted.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|byte| *byte == 0)
+    }
+    /// Serialises the summary as format version, number of hashes, number of bits (big endian) and the bits.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        buf.push(SUMMARY_FORMAT);
+        buf.push(self.num_hashes);
+        buf.extend_from_slice(&self.num_bits.to_be_bytes());
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 6 || buf[0] != SUMMARY_FORMAT {
+            return Err(D7Error::Parse("unsupported summary vector".into()));
+        }
+        let num_hashes = buf[1];
+        let num_bits = u32::from_be_bytes([buf[2], buf[3], buf[4], buf[5]]);
+        let bits = buf[6..].to_vec();
+        if num_hashes == 0 || num_bits == 0 || bits.len() != (num_bits as usize).div_ceil(8) {
+            return Err(D7Error::Parse("malformed summary vector".into()));
+        }
+        Ok(Self {
+            num_hashes,
+            num_bits,
+            bits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SummaryVector;
+
+    #[test]
+    fn summary_vector_test() {
+        let bids: Vec<String> = (0..1000)
+            .map(|i| format!("dtn://node{}/inbox-{}-0", i % 7, i))
+            .collect();
+        let mut summary = SummaryVector::with_capacity(bids.len(), 0.01);
+        assert!(summary.is_empty());
+        for bid in &bids {
+            summary.insert(bid);
+        }
+        assert!(bids.iter().all(|bid| summary.contains(bid)));
+        let false_positives = (0..1000)
+            .filter(|i| summary.contains(&format!("dtn://other/inbox-{}-0", i)))
+            .count();
+        assert!(false_positives < 30, "{} false positives", false_positives);
+
+        let decoded = SummaryVector::from_bytes(&summary.to_bytes()).unwrap();
+        assert_eq!(decoded, summary);
+        assert_eq!(summary.to_bytes().len(), summary.encoded_len());
+        assert!(SummaryVector::from_bytes(&[1, 3, 0, 0, 0, 64]).is_err());
+        assert!(SummaryVector::from_bytes(&[]).is_err());
+    }
+}